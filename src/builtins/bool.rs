@@ -1,7 +1,10 @@
+use crate::builtins::i32::I32Var;
 use crate::bvar::{AllocVar, AllocationMode, BVar};
 use crate::constraint_system::{ConstraintSystemRef, Element};
+use crate::options::Options;
+use crate::stack::Stack;
 use crate::treepp::*;
-use anyhow::Result;
+use anyhow::{Error, Result};
 use std::ops::{BitAnd, BitOr, BitXor, Not};
 
 #[derive(Debug, Clone)]
@@ -29,6 +32,20 @@ impl BVar for BoolVar {
     fn value(&self) -> Result<Self::Value> {
         Ok(self.value)
     }
+
+    fn value_bytes(&self) -> Vec<u8> {
+        vec![self.value as u8]
+    }
+
+    fn from_value_bytes(bytes: &[u8]) -> Result<Self::Value> {
+        match bytes {
+            [0] => Ok(false),
+            [1] => Ok(true),
+            _ => Err(Error::msg(
+                "A bool value must be encoded as a single 0 or 1 byte",
+            )),
+        }
+    }
 }
 
 impl AllocVar for BoolVar {
@@ -51,7 +68,7 @@ impl Not for &BoolVar {
 
     fn not(self) -> Self::Output {
         self.cs
-            .insert_script(bool_var_not, self.variables())
+            .insert_script("bool_var_not", bool_var_not, self.variables())
             .unwrap();
         BoolVar::new_function_output(&self.cs, !self.value).unwrap()
     }
@@ -68,7 +85,11 @@ impl BitAnd<&BoolVar> for &BoolVar {
 
     fn bitand(self, rhs: &BoolVar) -> Self::Output {
         self.cs
-            .insert_script(bool_var_and, vec![self.variable, rhs.variable])
+            .insert_script(
+                "bool_var_and",
+                bool_var_and,
+                vec![self.variable, rhs.variable],
+            )
             .unwrap();
         BoolVar::new_function_output(&self.cs, self.value & rhs.value).unwrap()
     }
@@ -85,7 +106,11 @@ impl BitOr<&BoolVar> for &BoolVar {
 
     fn bitor(self, rhs: &BoolVar) -> Self::Output {
         self.cs
-            .insert_script(bool_var_or, vec![self.variable, rhs.variable])
+            .insert_script(
+                "bool_var_or",
+                bool_var_or,
+                vec![self.variable, rhs.variable],
+            )
             .unwrap();
         BoolVar::new_function_output(&self.cs, self.value | rhs.value).unwrap()
     }
@@ -102,7 +127,11 @@ impl BitXor<&BoolVar> for &BoolVar {
 
     fn bitxor(self, rhs: &BoolVar) -> Self::Output {
         self.cs
-            .insert_script(bool_var_xor, vec![self.variable, rhs.variable])
+            .insert_script(
+                "bool_var_xor",
+                bool_var_xor,
+                vec![self.variable, rhs.variable],
+            )
             .unwrap();
         BoolVar::new_function_output(&self.cs, self.value ^ rhs.value).unwrap()
     }
@@ -120,7 +149,7 @@ impl BoolVar {
     pub fn verify(self) {
         assert!(self.value);
         self.cs
-            .insert_script(bool_var_verify, vec![self.variable])
+            .insert_script("bool_var_verify", bool_var_verify, vec![self.variable])
             .unwrap()
     }
 }
@@ -130,3 +159,130 @@ fn bool_var_verify() -> Script {
         OP_VERIFY
     }
 }
+
+impl BoolVar {
+    /// Verifies that each of the bits is boolean and exactly one of them is set.
+    pub fn assert_one_hot(bits: &[BoolVar]) -> Result<()> {
+        let cs = Self::one_hot_cs(bits)?;
+        cs.insert_script_complex(
+            "bool_var_one_hot_verify",
+            bool_var_one_hot_verify,
+            bits.iter().map(|bit| bit.variable),
+            &Options::new().with_u32("len", bits.len() as u32),
+        )
+    }
+
+    /// Verifies that the bits are one-hot and returns the index of the bit that is set.
+    pub fn one_hot_index(bits: &[BoolVar]) -> Result<I32Var> {
+        let cs = Self::one_hot_cs(bits)?;
+        cs.insert_script_complex(
+            "bool_var_one_hot_index",
+            bool_var_one_hot_index,
+            bits.iter().map(|bit| bit.variable),
+            &Options::new().with_u32("len", bits.len() as u32),
+        )?;
+        let index = bits.iter().position(|bit| bit.value).unwrap();
+        I32Var::new_function_output(&cs, index as i32)
+    }
+
+    fn one_hot_cs(bits: &[BoolVar]) -> Result<ConstraintSystemRef> {
+        assert!(!bits.is_empty());
+        assert_eq!(bits.iter().filter(|bit| bit.value).count(), 1);
+
+        let mut cs = bits[0].cs();
+        for bit in bits.iter().skip(1) {
+            cs = cs.try_and(&bit.cs())?;
+        }
+        Ok(cs)
+    }
+}
+
+fn bool_var_one_hot_verify(_: &mut Stack, options: &Options) -> Result<Script> {
+    let len = options.get_u32("len")?;
+    Ok(script! {
+        OP_DUP 0 2 OP_WITHIN OP_VERIFY
+        for _ in 1..len {
+            OP_SWAP
+            OP_DUP 0 2 OP_WITHIN OP_VERIFY
+            OP_ADD
+        }
+        1 OP_NUMEQUALVERIFY
+    })
+}
+
+fn bool_var_one_hot_index(_: &mut Stack, options: &Options) -> Result<Script> {
+    let len = options.get_u32("len")?;
+    Ok(script! {
+        // the last bit is on the top of the stack; keep (sum, index) as the accumulator
+        OP_DUP 0 2 OP_WITHIN OP_VERIFY
+        OP_DUP OP_IF { len - 1 } OP_ELSE 0 OP_ENDIF
+        for i in (0..len - 1).rev() {
+            OP_ROT
+            OP_DUP 0 2 OP_WITHIN OP_VERIFY
+            OP_DUP OP_IF OP_SWAP { i } OP_ADD OP_SWAP OP_ENDIF
+            OP_ROT OP_ADD OP_SWAP
+        }
+        OP_SWAP 1 OP_NUMEQUALVERIFY
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use crate::builtins::bool::BoolVar;
+    use crate::bvar::AllocVar;
+    use crate::constraint_system::ConstraintSystem;
+    use crate::test_program;
+    use crate::treepp::*;
+
+    #[test]
+    fn test_one_hot() {
+        let cs = ConstraintSystem::new_ref();
+
+        let bits = [false, false, true, false]
+            .iter()
+            .map(|&bit| BoolVar::new_hint(&cs, bit).unwrap())
+            .collect::<Vec<_>>();
+        BoolVar::assert_one_hot(&bits).unwrap();
+        let index = BoolVar::one_hot_index(&bits).unwrap();
+        assert_eq!(index.value, 2);
+        cs.set_program_output(&index).unwrap();
+
+        test_program(cs, script! { 2 }).unwrap();
+
+        for len in 1..5 {
+            for pos in 0..len {
+                let cs = ConstraintSystem::new_ref();
+                let bits = (0..len)
+                    .map(|i| BoolVar::new_program_input(&cs, i == pos).unwrap())
+                    .collect::<Vec<_>>();
+                let index = BoolVar::one_hot_index(&bits).unwrap();
+                cs.set_program_output(&index).unwrap();
+
+                test_program(cs, script! { { pos as i32 } }).unwrap();
+            }
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_one_hot_all_zero() {
+        let cs = ConstraintSystem::new_ref();
+
+        let bits = (0..4)
+            .map(|_| BoolVar::new_constant(&cs, false).unwrap())
+            .collect::<Vec<_>>();
+        BoolVar::assert_one_hot(&bits).unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_one_hot_two_hot() {
+        let cs = ConstraintSystem::new_ref();
+
+        let bits = [true, false, true]
+            .iter()
+            .map(|&bit| BoolVar::new_constant(&cs, bit).unwrap())
+            .collect::<Vec<_>>();
+        BoolVar::assert_one_hot(&bits).unwrap();
+    }
+}
@@ -1,10 +1,11 @@
+use crate::builtins::i32::I32Var;
 use crate::builtins::str::StrVar;
-use crate::bvar::{dummy_script, AllocVar, AllocationMode, BVar};
+use crate::bvar::{dummy_script, fold, AllocVar, AllocationMode, BVar};
 use crate::constraint_system::{ConstraintSystemRef, Element};
 use crate::options::Options;
 use crate::stack::Stack;
 use crate::treepp::*;
-use anyhow::Result;
+use anyhow::{Error, Result};
 use bitcoin::opcodes::all::OP_CAT;
 use bitcoin::opcodes::Ordinary::OP_SHA256;
 use bitcoin::script::write_scriptint;
@@ -38,6 +39,14 @@ impl BVar for HashVar {
     fn value(&self) -> Result<Self::Value> {
         Ok(self.value.clone())
     }
+
+    fn value_bytes(&self) -> Vec<u8> {
+        self.value.clone()
+    }
+
+    fn from_value_bytes(bytes: &[u8]) -> Result<Self::Value> {
+        Ok(bytes.to_vec())
+    }
 }
 
 impl AllocVar for HashVar {
@@ -65,7 +74,7 @@ impl Add for &HashVar {
         Update::update(&mut sha256, &self.value);
         let hash = sha256.finalize().to_vec();
 
-        cs.insert_script(hash_combine, [rhs.variable, self.variable])
+        cs.insert_script("hash_combine", hash_combine, [rhs.variable, self.variable])
             .unwrap();
         HashVar::new_function_output(&cs, hash).unwrap()
     }
@@ -95,7 +104,7 @@ impl<T: BVar> From<&T> for HashVar {
 
         let len = variables.len() as u32;
         let options = Options::new().with_u32("len", len);
-        cs.insert_script_complex(hash_many, variables, &options)
+        cs.insert_script_complex("hash_many", hash_many, variables, &options)
             .unwrap();
 
         HashVar::new_function_output(&cs, cur_hash.unwrap()).unwrap()
@@ -135,7 +144,7 @@ impl<T: BVar> From<&[T]> for HashVar {
 
         let len = variables.len() as u32;
         let options = Options::new().with_u32("len", len);
-        cs.insert_script_complex(hash_many, variables, &options)
+        cs.insert_script_complex("hash_many", hash_many, variables, &options)
             .unwrap();
 
         HashVar::new_function_output(&cs, cur_hash.unwrap()).unwrap()
@@ -145,11 +154,115 @@ impl<T: BVar> From<&[T]> for HashVar {
 impl From<&HashVar> for StrVar {
     fn from(v: &HashVar) -> StrVar {
         let cs = v.cs();
-        cs.insert_script(dummy_script, v.variables()).unwrap();
+        cs.insert_script("dummy_script", dummy_script, v.variables())
+            .unwrap();
         StrVar::new_function_output(&cs, v.value().unwrap()).unwrap()
     }
 }
 
+impl HashVar {
+    /// Allocates the initial digest of a transcript with domain separation, which is the
+    /// SHA256 hash of `tag`.
+    pub fn new_with_tag(cs: &ConstraintSystemRef, tag: &[u8]) -> Result<HashVar> {
+        HashVar::new_constant(cs, Sha256::digest(tag).to_vec())
+    }
+
+    /// Selects `options[index]`, after verifying that `index` is within the range of `options`.
+    /// The script checks the range too, so that a witness with an out-of-range index is rejected.
+    pub fn select_index(options: &[HashVar], index: &I32Var) -> Result<HashVar> {
+        if index.value < 0 || index.value as usize >= options.len() {
+            return Err(Error::msg(format!(
+                "The index {} is out of the range of the {} options",
+                index.value,
+                options.len()
+            )));
+        }
+
+        let mut cs = index.cs();
+        for option in options.iter() {
+            cs = cs.try_and(&option.cs)?;
+        }
+
+        let mut variables = options
+            .iter()
+            .map(|option| option.variable)
+            .collect::<Vec<_>>();
+        variables.push(index.variable);
+
+        cs.insert_script_complex(
+            "hash_select_index",
+            hash_select_index,
+            variables,
+            &Options::new().with_u32("len", options.len() as u32),
+        )?;
+        HashVar::new_function_output(&cs, options[index.value as usize].value.clone())
+    }
+}
+
+impl HashVar {
+    /// Chains the hashes from the left, each step combining the running hash with the next leaf
+    /// as in `&acc + &leaf`.
+    pub fn chain(leaves: &[HashVar]) -> HashVar {
+        assert!(!leaves.is_empty());
+        fold(leaves[0].clone(), &leaves[1..], |acc, leaf| &acc + leaf)
+    }
+}
+
+impl HashVar {
+    /// Verifies that `self` is the SHA256 digest of the concatenation of `parts`.
+    pub fn assert_is_hash_of(&self, parts: &[&StrVar]) -> Result<()> {
+        assert!(!parts.is_empty());
+
+        let mut sha256 = Sha256::new();
+        for part in parts.iter() {
+            Update::update(&mut sha256, &part.value);
+        }
+        assert_eq!(sha256.finalize().to_vec(), self.value);
+
+        let mut cs = self.cs();
+        let mut variables = vec![self.variable];
+        for part in parts.iter() {
+            cs = cs.try_and(&part.cs)?;
+            variables.push(part.variable);
+        }
+
+        cs.insert_script_complex(
+            "hash_is_hash_of",
+            hash_is_hash_of,
+            variables,
+            &Options::new().with_u32("len", parts.len() as u32),
+        )
+    }
+}
+
+fn hash_is_hash_of(_: &mut Stack, options: &Options) -> Result<Script> {
+    let len = options.get_u32("len")?;
+    Ok(script! {
+        for _ in 0..len - 1 {
+            OP_CAT
+        }
+        OP_SHA256
+        OP_EQUALVERIFY
+    })
+}
+
+fn hash_select_index(_: &mut Stack, options: &Options) -> Result<Script> {
+    let len = options.get_u32("len")?;
+    Ok(script! {
+        OP_DUP 0 { len } OP_WITHIN OP_VERIFY
+        // the option with index i is at depth (len - 1 - i) once the index is consumed
+        { len - 1 } OP_SWAP OP_SUB OP_PICK
+        OP_TOALTSTACK
+        for _ in 0..len / 2 {
+            OP_2DROP
+        }
+        if len % 2 == 1 {
+            OP_DROP
+        }
+        OP_FROMALTSTACK
+    })
+}
+
 fn hash_many(_: &mut Stack, options: &Options) -> Result<Script> {
     let len = options.get_u32("len")?;
     Ok(script! {
@@ -169,3 +282,133 @@ pub fn bitcoin_num_to_bytes(v: i64) -> Vec<u8> {
     let l = write_scriptint(&mut buf, v);
     buf[0..l].to_vec()
 }
+
+#[cfg(test)]
+mod test {
+    use crate::builtins::hash::{bitcoin_num_to_bytes, HashVar};
+    use crate::builtins::i32::I32Var;
+    use crate::builtins::str::StrVar;
+    use crate::bvar::{AllocVar, BVar};
+    use crate::compiler::Compiler;
+    use crate::constraint_system::ConstraintSystem;
+    use crate::treepp::*;
+    use crate::{execute_tapscript, test_program};
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha20Rng;
+    use sha2::digest::Update;
+    use sha2::{Digest, Sha256};
+
+    #[test]
+    fn test_chain() {
+        let mut prng = ChaCha20Rng::seed_from_u64(0);
+        let cs = ConstraintSystem::new_ref();
+
+        let leaves = (0..5)
+            .map(|_| {
+                let leaf: [u8; 32] = prng.gen();
+                HashVar::new_program_input(&cs, leaf.to_vec()).unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        let chained = HashVar::chain(&leaves);
+
+        let mut expected = leaves[0].clone();
+        for leaf in leaves[1..].iter() {
+            expected = &expected + leaf;
+        }
+        assert_eq!(chained.value, expected.value);
+
+        chained.equalverify(&expected).unwrap();
+        cs.set_program_output(&chained).unwrap();
+        test_program(cs, script! { { expected.value.clone() } }).unwrap();
+    }
+
+    #[test]
+    fn test_select_index() {
+        let mut prng = ChaCha20Rng::seed_from_u64(0);
+
+        let values = (0..4)
+            .map(|_| prng.gen::<[u8; 32]>().to_vec())
+            .collect::<Vec<_>>();
+
+        for i in 0..4 {
+            let cs = ConstraintSystem::new_ref();
+
+            let options = values
+                .iter()
+                .map(|value| HashVar::new_program_input(&cs, value.clone()).unwrap())
+                .collect::<Vec<_>>();
+            let index = I32Var::new_program_input(&cs, i as i32).unwrap();
+
+            let selected = HashVar::select_index(&options, &index).unwrap();
+            assert_eq!(selected.value, values[i]);
+
+            cs.set_program_output(&selected).unwrap();
+            test_program(cs, script! { { values[i].clone() } }).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_select_index_out_of_range() {
+        let cs = ConstraintSystem::new_ref();
+
+        let options = vec![HashVar::new_constant(&cs, vec![0u8; 32]).unwrap()];
+        let index = I32Var::new_constant(&cs, 1).unwrap();
+        assert!(HashVar::select_index(&options, &index).is_err());
+        assert!(HashVar::select_index(&[], &index).is_err());
+
+        // the script rejects an out-of-range index given in the witness
+        let cs = ConstraintSystem::new_ref();
+
+        let options = (0..4)
+            .map(|i| HashVar::new_program_input(&cs, vec![i; 32]).unwrap())
+            .collect::<Vec<_>>();
+        let index = I32Var::new_program_input(&cs, 1).unwrap();
+        let selected = HashVar::select_index(&options, &index).unwrap();
+        cs.set_program_output(&selected).unwrap();
+
+        let program = Compiler::compile(cs).unwrap();
+        let mut witness = program.to_witness().unwrap();
+        let exec = execute_tapscript(program.script.clone(), witness.clone(), true).unwrap();
+        assert!(exec.result().unwrap().success);
+
+        for i in [-1, 4] {
+            *witness.last_mut().unwrap() = bitcoin_num_to_bytes(i);
+            let exec = execute_tapscript(program.script.clone(), witness.clone(), true).unwrap();
+            assert!(!exec.result().unwrap().success);
+        }
+    }
+
+    #[test]
+    fn test_assert_is_hash_of() {
+        let mut prng = ChaCha20Rng::seed_from_u64(0);
+
+        let a_val: [u8; 20] = prng.gen();
+        let b_val: [u8; 32] = prng.gen();
+
+        let mut sha256 = Sha256::new();
+        Update::update(&mut sha256, &a_val);
+        Update::update(&mut sha256, &b_val);
+        let h_val = sha256.finalize().to_vec();
+
+        let cs = ConstraintSystem::new_ref();
+
+        let a = StrVar::new_program_input(&cs, a_val.to_vec()).unwrap();
+        let b = StrVar::new_program_input(&cs, b_val.to_vec()).unwrap();
+        let h = HashVar::new_hint(&cs, h_val).unwrap();
+        h.assert_is_hash_of(&[&a, &b]).unwrap();
+
+        test_program(cs, script! {}).unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_assert_is_hash_of_wrong_digest() {
+        let cs = ConstraintSystem::new_ref();
+
+        let a = StrVar::new_constant(&cs, b"hello".to_vec()).unwrap();
+        let b = StrVar::new_constant(&cs, b"world".to_vec()).unwrap();
+        let h = HashVar::new_constant(&cs, vec![0u8; 32]).unwrap();
+        h.assert_is_hash_of(&[&a, &b]).unwrap();
+    }
+}
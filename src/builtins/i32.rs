@@ -1,10 +1,15 @@
+use crate::builtins::bool::BoolVar;
 use crate::builtins::u8::U8Var;
 use crate::bvar::{AllocVar, AllocationMode, BVar};
 use crate::constraint_system::{ConstraintSystemRef, Element};
 use crate::options::Options;
+use crate::reference_eval::{nums, ReferenceEval};
+use crate::script_generator::{GadgetSpec, ScriptGenerator};
 use crate::stack::Stack;
 use crate::treepp::*;
-use anyhow::Result;
+use anyhow::{Error, Result};
+use rand::Rng;
+use rand_chacha::ChaCha20Rng;
 use std::ops::{Add, Sub};
 
 #[derive(Debug, Clone)]
@@ -32,6 +37,17 @@ impl BVar for I32Var {
     fn value(&self) -> Result<Self::Value> {
         Ok(self.value)
     }
+
+    fn value_bytes(&self) -> Vec<u8> {
+        self.value.to_le_bytes().to_vec()
+    }
+
+    fn from_value_bytes(bytes: &[u8]) -> Result<Self::Value> {
+        let bytes: [u8; 4] = bytes
+            .try_into()
+            .map_err(|_| Error::msg("An i32 value must be encoded in 4 bytes"))?;
+        Ok(i32::from_le_bytes(bytes))
+    }
 }
 
 impl AllocVar for I32Var {
@@ -53,16 +69,7 @@ impl Add for &I32Var {
     type Output = I32Var;
 
     fn add(self, rhs: Self) -> Self::Output {
-        let res = self.value.checked_add(rhs.value).unwrap();
-        assert!(res > i32::MIN);
-
-        let cs = self.cs().and(&rhs.cs);
-
-        cs.insert_script(i32_add, [self.variable, rhs.variable])
-            .unwrap();
-
-        let res_var = I32Var::new_variable(&cs, res, AllocationMode::FunctionOutput).unwrap();
-        res_var
+        self.checked_add(rhs).unwrap()
     }
 }
 
@@ -75,8 +82,13 @@ impl Add<&U8Var> for &I32Var {
 
         let cs = self.cs().and(&rhs.cs);
 
-        cs.insert_script(i32_add, [self.variable, rhs.variable])
-            .unwrap();
+        cs.insert_script_with_spec(
+            "i32_add",
+            i32_add,
+            [self.variable, rhs.variable],
+            GadgetSpec::commutative_binary(),
+        )
+        .unwrap();
 
         let res_var = I32Var::new_variable(&cs, res, AllocationMode::FunctionOutput).unwrap();
         res_var
@@ -93,16 +105,7 @@ impl Sub for &I32Var {
     type Output = I32Var;
 
     fn sub(self, rhs: Self) -> Self::Output {
-        let res = self.value.checked_sub(rhs.value).unwrap();
-        assert!(res > i32::MIN);
-
-        let cs = self.cs().and(&rhs.cs);
-
-        cs.insert_script(i32_sub, [self.variable, rhs.variable])
-            .unwrap();
-
-        let res_var = I32Var::new_variable(&cs, res, AllocationMode::FunctionOutput).unwrap();
-        res_var
+        self.checked_sub(rhs).unwrap()
     }
 }
 
@@ -115,7 +118,7 @@ impl Sub<&U8Var> for &I32Var {
 
         let cs = self.cs().and(&rhs.cs);
 
-        cs.insert_script(i32_sub, [self.variable, rhs.variable])
+        cs.insert_script("i32_sub", i32_sub, [self.variable, rhs.variable])
             .unwrap();
 
         let res_var = I32Var::new_variable(&cs, res, AllocationMode::FunctionOutput).unwrap();
@@ -130,28 +133,145 @@ fn i32_sub() -> Script {
 }
 
 impl I32Var {
+    /// Adds two I32Vars, returning an error instead of panicking if the result is out of range.
+    pub fn checked_add(&self, rhs: &I32Var) -> Result<I32Var> {
+        let res = self
+            .value
+            .checked_add(rhs.value)
+            .filter(|&res| res > i32::MIN)
+            .ok_or_else(|| Error::msg("The addition of the I32Vars overflows"))?;
+
+        let cs = self.cs().try_and(&rhs.cs)?;
+        cs.insert_script_with_spec(
+            "i32_add",
+            i32_add,
+            [self.variable, rhs.variable],
+            GadgetSpec::commutative_binary(),
+        )?;
+        I32Var::new_variable(&cs, res, AllocationMode::FunctionOutput)
+    }
+
+    /// Subtracts two I32Vars, returning an error instead of panicking if the result is out of
+    /// range.
+    pub fn checked_sub(&self, rhs: &I32Var) -> Result<I32Var> {
+        let res = self
+            .value
+            .checked_sub(rhs.value)
+            .filter(|&res| res > i32::MIN)
+            .ok_or_else(|| Error::msg("The subtraction of the I32Vars overflows"))?;
+
+        let cs = self.cs().try_and(&rhs.cs)?;
+        cs.insert_script("i32_sub", i32_sub, [self.variable, rhs.variable])?;
+        I32Var::new_variable(&cs, res, AllocationMode::FunctionOutput)
+    }
+
     pub fn check_format(&self) -> Result<()> {
-        self.cs.insert_script(i32_check_format, [self.variable])
+        self.cs
+            .insert_script("i32_check_format", i32_check_format, [self.variable])
     }
 
-    pub fn to_positive_limbs(&self, l: usize, w: usize) -> Result<Vec<U8Var>> {
-        assert!(w <= 8);
-        assert!(self.value >= 0);
+    pub fn assert_not_equal(&self, rhs: &I32Var) -> Result<()> {
+        assert_ne!(self.value, rhs.value);
 
-        let mut value = self.value as u32;
-        let mut res = vec![];
+        let cs = self.cs().try_and(&rhs.cs)?;
+        cs.insert_script(
+            "i32_assert_not_equal",
+            i32_assert_not_equal,
+            [self.variable, rhs.variable],
+        )
+    }
 
-        for _ in 0..l {
-            res.push(value & ((1 << w) - 1));
-            value >>= w;
-        }
+    pub fn not_equal(&self, rhs: &I32Var) -> Result<BoolVar> {
+        let cs = self.cs().try_and(&rhs.cs)?;
+        cs.insert_script(
+            "i32_not_equal",
+            i32_not_equal,
+            [self.variable, rhs.variable],
+        )?;
+        BoolVar::new_function_output(&cs, self.value != rhs.value)
+    }
+
+    /// Returns `-self` if the bit is set, and `self` otherwise.
+    pub fn conditional_negate(&self, bit: &BoolVar) -> Result<I32Var> {
+        let cs = self.cs().try_and(&bit.cs)?;
+        cs.insert_script(
+            "i32_conditional_negate",
+            i32_conditional_negate,
+            [self.variable, bit.variable],
+        )?;
+
+        let res = if bit.value { -self.value } else { self.value };
+        I32Var::new_function_output(&cs, res)
+    }
+
+    /// Verifies that the value is zero if the bit is set, so that a constraint can be enabled by
+    /// a selector.
+    pub fn assert_zero_if(&self, bit: &BoolVar) -> Result<()> {
+        assert!(!bit.value || self.value == 0);
+
+        let cs = self.cs().try_and(&bit.cs)?;
+        cs.insert_script(
+            "i32_assert_zero_if",
+            i32_assert_zero_if,
+            [self.variable, bit.variable],
+        )
+    }
+
+    /// Returns `self + addend` if the bit is set, and `self` otherwise, which is useful to
+    /// accumulate sparse sums.
+    pub fn add_if(&self, addend: &I32Var, bit: &BoolVar) -> Result<I32Var> {
+        let res = if bit.value {
+            self.value
+                .checked_add(addend.value)
+                .filter(|&res| res > i32::MIN)
+                .ok_or_else(|| Error::msg("The addition of the I32Vars overflows"))?
+        } else {
+            self.value
+        };
+
+        let cs = self.cs().try_and(&addend.cs)?.try_and(&bit.cs)?;
+        cs.insert_script(
+            "i32_add_if",
+            i32_add_if,
+            [self.variable, addend.variable, bit.variable],
+        )?;
+        I32Var::new_function_output(&cs, res)
+    }
+
+    /// Returns `2 * self`, which only needs one copy of the value on the stack.
+    pub fn double(&self) -> Result<I32Var> {
+        let res = self
+            .value
+            .checked_mul(2)
+            .filter(|&res| res > i32::MIN)
+            .ok_or_else(|| Error::msg("The doubling of the I32Var overflows"))?;
+
+        self.cs
+            .insert_script("i32_double", i32_double, [self.variable])?;
+        I32Var::new_function_output(&self.cs, res)
+    }
+
+    /// Verifies that the value is one of the elements of a small constant set.
+    pub fn assert_in_set(&self, set: &[i32]) -> Result<()> {
+        assert!(set.contains(&self.value));
+
+        self.cs.insert_script_complex(
+            "i32_assert_in_set",
+            i32_assert_in_set,
+            [self.variable],
+            &Options::new().with_multi_u32("set", set.iter().map(|&v| v as u32).collect()),
+        )
+    }
 
-        assert_eq!(value, 0);
+    pub fn to_positive_limbs(&self, l: usize, w: usize) -> Result<Vec<U8Var>> {
+        let res = positive_limbs(self.value, l, w);
 
         let cs = self.cs();
         let mut res_vars = vec![];
-        for &v in res.iter() {
-            res_vars.push(U8Var::new_hint(&cs, v as u8)?);
+        for (i, &v) in res.iter().enumerate() {
+            let res_var = U8Var::new_hint(&cs, v)?;
+            cs.describe_hint(res_var.variable, format!("limb {} of {}", i, self.variable))?;
+            res_vars.push(res_var);
         }
 
         let mut variables = vec![self.variable];
@@ -160,6 +280,7 @@ impl I32Var {
         }
 
         cs.insert_script_complex(
+            "i32_to_positive_limbs_check",
             i32_to_positive_limbs_check,
             variables,
             &Options::new()
@@ -169,6 +290,77 @@ impl I32Var {
 
         Ok(res_vars)
     }
+
+    /// Recomposes the value from `w`-bit limbs, the least significant limb first, which reverses
+    /// `to_positive_limbs`. The limbs are assumed to be already checked to have `w` bits.
+    pub fn from_positive_limbs(limbs: &[U8Var], w: usize) -> Result<I32Var> {
+        assert!(!limbs.is_empty());
+        assert!(w <= 8);
+
+        let mut value = 0u64;
+        for limb in limbs.iter().rev() {
+            assert!((limb.value as u64) < (1 << w));
+            value = (value << w) + limb.value as u64;
+            assert!(value <= i32::MAX as u64);
+        }
+
+        let mut cs = limbs[0].cs();
+        for limb in limbs[1..].iter() {
+            cs = cs.try_and(&limb.cs)?;
+        }
+
+        cs.insert_script_complex(
+            "i32_from_positive_limbs",
+            i32_from_positive_limbs,
+            limbs.iter().map(|limb| limb.variable).collect::<Vec<_>>(),
+            &Options::new()
+                .with_u32("w", w as u32)
+                .with_u32("l", limbs.len() as u32),
+        )?;
+        I32Var::new_function_output(&cs, value as i32)
+    }
+
+    /// Decomposes a constant into `l` limbs of `w` bits, like `to_positive_limbs`. Since the
+    /// value is known when the program is built, the limbs are pushed as constants and no
+    /// gadget is needed to check them.
+    pub fn constant_to_positive_limbs(
+        cs: &ConstraintSystemRef,
+        value: i32,
+        l: usize,
+        w: usize,
+    ) -> Result<Vec<U8Var>> {
+        U8Var::new_constant_vec(cs, &positive_limbs(value, l, w))
+    }
+}
+
+fn i32_from_positive_limbs(_: &mut Stack, options: &Options) -> Result<Script> {
+    let w = options.get_u32("w")?;
+    let l = options.get_u32("l")?;
+
+    Ok(script! {
+        for _ in 1..l {
+            for _ in 0..w {
+                OP_DUP OP_ADD
+            }
+            OP_ADD
+        }
+    })
+}
+
+fn positive_limbs(value: i32, l: usize, w: usize) -> Vec<u8> {
+    assert!(w <= 8);
+    assert!(value >= 0);
+
+    let mut value = value as u32;
+    let mut res = vec![];
+
+    for _ in 0..l {
+        res.push((value & ((1 << w) - 1)) as u8);
+        value >>= w;
+    }
+
+    assert_eq!(value, 0);
+    res
 }
 
 fn i32_check_format() -> Script {
@@ -177,6 +369,53 @@ fn i32_check_format() -> Script {
     }
 }
 
+fn i32_assert_not_equal() -> Script {
+    script! {
+        OP_EQUAL OP_NOT OP_VERIFY
+    }
+}
+
+fn i32_not_equal() -> Script {
+    script! {
+        OP_EQUAL OP_NOT
+    }
+}
+
+fn i32_conditional_negate() -> Script {
+    script! {
+        OP_IF OP_NEGATE OP_ENDIF
+    }
+}
+
+fn i32_assert_zero_if() -> Script {
+    script! {
+        OP_IF OP_NOT OP_VERIFY OP_ELSE OP_DROP OP_ENDIF
+    }
+}
+
+fn i32_add_if() -> Script {
+    script! {
+        OP_IF OP_ADD OP_ELSE OP_DROP OP_ENDIF
+    }
+}
+
+fn i32_double() -> Script {
+    script! {
+        OP_DUP OP_ADD
+    }
+}
+
+fn i32_assert_in_set(_: &mut Stack, options: &Options) -> Result<Script> {
+    let set = options.get_multi_u32("set")?;
+    Ok(script! {
+        OP_DUP { set[0] as i32 } OP_NUMEQUAL
+        for &v in set[1..].iter() {
+            OP_OVER { v as i32 } OP_NUMEQUAL OP_BOOLOR
+        }
+        OP_NIP OP_VERIFY
+    })
+}
+
 fn i32_to_positive_limbs_check(_: &mut Stack, options: &Options) -> Result<Script> {
     let w = options.get_u32("w")? as usize;
     let l = options.get_u32("l")? as usize;
@@ -203,11 +442,61 @@ fn i32_to_positive_limbs_check(_: &mut Stack, options: &Options) -> Result<Scrip
     })
 }
 
+struct I32AddReference;
+
+impl ReferenceEval for I32AddReference {
+    fn name(&self) -> &'static str {
+        "i32_add"
+    }
+
+    fn generator(&self) -> ScriptGenerator {
+        ScriptGenerator::Simple(i32_add)
+    }
+
+    fn sample(&self, prng: &mut ChaCha20Rng) -> (Vec<Element>, Options) {
+        let a = prng.gen_range(-(1 << 30)..(1 << 30));
+        let b = prng.gen_range(-(1 << 30)..(1 << 30));
+        (vec![Element::Num(a), Element::Num(b)], Options::new())
+    }
+
+    fn eval(&self, inputs: &[Element], _: &Options) -> Result<Vec<Element>> {
+        let [a, b] = nums(inputs)?;
+        Ok(vec![Element::Num(a + b)])
+    }
+}
+
+struct I32SubReference;
+
+impl ReferenceEval for I32SubReference {
+    fn name(&self) -> &'static str {
+        "i32_sub"
+    }
+
+    fn generator(&self) -> ScriptGenerator {
+        ScriptGenerator::Simple(i32_sub)
+    }
+
+    fn sample(&self, prng: &mut ChaCha20Rng) -> (Vec<Element>, Options) {
+        I32AddReference.sample(prng)
+    }
+
+    fn eval(&self, inputs: &[Element], _: &Options) -> Result<Vec<Element>> {
+        let [a, b] = nums(inputs)?;
+        Ok(vec![Element::Num(a - b)])
+    }
+}
+
+pub(crate) fn reference_evals() -> Vec<Box<dyn ReferenceEval>> {
+    vec![Box::new(I32AddReference), Box::new(I32SubReference)]
+}
+
 #[cfg(test)]
 mod test {
+    use crate::builtins::bool::BoolVar;
     use crate::builtins::i32::I32Var;
     use crate::builtins::u8::U8Var;
-    use crate::bvar::{AllocVar, AllocationMode};
+    use crate::bvar::{AllocVar, AllocationMode, BVar};
+    use crate::compiler::{Compiler, CompilerOptions};
     use crate::constraint_system::{ConstraintSystem, Element};
     use crate::test_program;
     use crate::treepp::*;
@@ -225,6 +514,9 @@ mod test {
         let c = &a + &b;
         c.check_format().unwrap();
         cs.set_program_output(&c).unwrap();
+        // the spec of the addition agrees with its script
+        Compiler::compile_with_options(cs.clone(), &CompilerOptions::new().with_strict(true))
+            .unwrap();
         test_program(cs, script! { { i32::MAX - 1 } }).unwrap();
     }
 
@@ -238,6 +530,9 @@ mod test {
         let c = &a + &b;
         c.check_format().unwrap();
         cs.set_program_output(&c).unwrap();
+        // the spec of the addition agrees with its script
+        Compiler::compile_with_options(cs.clone(), &CompilerOptions::new().with_strict(true))
+            .unwrap();
         test_program(cs, script! { { i32::MAX } }).unwrap();
     }
 
@@ -326,6 +621,112 @@ mod test {
         let _ = &a - &b;
     }
 
+    #[test]
+    fn test_checked_add_sub_i32() {
+        let cs = ConstraintSystem::new_ref();
+
+        let max = I32Var::new_constant(&cs, i32::MAX).unwrap();
+        let min = I32Var::new_constant(&cs, i32::MIN + 1).unwrap();
+        let one = I32Var::new_constant(&cs, 1).unwrap();
+        let minus_one = I32Var::new_constant(&cs, -1).unwrap();
+
+        assert!(max.checked_add(&one).is_err());
+        assert!(max.checked_sub(&minus_one).is_err());
+        assert!(min.checked_sub(&one).is_err());
+        assert!(min.checked_add(&minus_one).is_err());
+
+        let c = max.checked_add(&minus_one).unwrap();
+        let d = min.checked_sub(&minus_one).unwrap();
+        cs.set_program_output(&c).unwrap();
+        cs.set_program_output(&d).unwrap();
+        test_program(cs, script! { { i32::MAX - 1 } { i32::MIN + 2 } }).unwrap();
+    }
+
+    #[test]
+    fn test_conditional_negate() {
+        for bit in [false, true] {
+            let cs = ConstraintSystem::new_ref();
+
+            let a = I32Var::new_constant(&cs, 1234).unwrap();
+            let b = BoolVar::new_constant(&cs, bit).unwrap();
+
+            let c = a.conditional_negate(&b).unwrap();
+            let expected = if bit { -1234 } else { 1234 };
+            assert_eq!(c.value, expected);
+
+            cs.set_program_output(&c).unwrap();
+            test_program(cs, script! { { expected } }).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_assert_zero_if() {
+        for (value, bit) in [(0, true), (0, false), (17, false)] {
+            let cs = ConstraintSystem::new_ref();
+
+            let a = I32Var::new_program_input(&cs, value).unwrap();
+            let b = BoolVar::new_constant(&cs, bit).unwrap();
+            a.assert_zero_if(&b).unwrap();
+
+            test_program(cs, script! {}).unwrap();
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_assert_zero_if_nonzero() {
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_program_input(&cs, 17).unwrap();
+        let b = BoolVar::new_constant(&cs, true).unwrap();
+        a.assert_zero_if(&b).unwrap();
+    }
+
+    #[test]
+    fn test_add_if() {
+        for bit in [false, true] {
+            let cs = ConstraintSystem::new_ref();
+
+            let a = I32Var::new_program_input(&cs, 1234).unwrap();
+            let b = I32Var::new_constant(&cs, -34).unwrap();
+            let c = BoolVar::new_constant(&cs, bit).unwrap();
+
+            let d = a.add_if(&b, &c).unwrap();
+            let expected = if bit { 1200 } else { 1234 };
+            assert_eq!(d.value, expected);
+
+            cs.set_program_output(&d).unwrap();
+            test_program(cs, script! { { expected } }).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_cost_breakdown() {
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_constant(&cs, 1).unwrap();
+        let mut sum = a.clone();
+        for _ in 0..5 {
+            sum = &sum + &a;
+        }
+        sum.check_format().unwrap();
+        cs.set_program_output(&sum).unwrap();
+
+        // `u8_add` has the same body as `i32_add`, so the two functions may share an address
+        let b = U8Var::new_constant(&cs, 2).unwrap();
+        let c = &b + &b;
+        cs.set_program_output(&c).unwrap();
+
+        assert!(cs.cost_breakdown().is_err());
+        Compiler::compile(cs.clone()).unwrap();
+
+        let costs = cs.cost_breakdown().unwrap();
+        assert_eq!(costs["i32_add"], (5, 5));
+        assert_eq!(costs["i32_check_format"], (1, 2));
+        assert_eq!(costs["u8_add"], (1, 1));
+        assert_eq!(costs.len(), 3);
+    }
+
     #[test]
     fn test_check_format() {
         let cs = ConstraintSystem::new_ref();
@@ -348,6 +749,44 @@ mod test {
         test_program(cs, script! {}).unwrap();
     }
 
+    #[test]
+    fn test_assert_not_equal() {
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_constant(&cs, 5).unwrap();
+        let b = I32Var::new_constant(&cs, -5).unwrap();
+        a.assert_not_equal(&b).unwrap();
+        test_program(cs, script! {}).unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_assert_not_equal_on_equal_values() {
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_constant(&cs, 5).unwrap();
+        let b = I32Var::new_constant(&cs, 5).unwrap();
+        a.assert_not_equal(&b).unwrap();
+    }
+
+    #[test]
+    fn test_not_equal() {
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_constant(&cs, 5).unwrap();
+        let b = I32Var::new_constant(&cs, -5).unwrap();
+        let c = I32Var::new_constant(&cs, 5).unwrap();
+
+        let res1 = a.not_equal(&b).unwrap();
+        let res2 = a.not_equal(&c).unwrap();
+        assert!(res1.value);
+        assert!(!res2.value);
+
+        cs.set_program_output(&res1).unwrap();
+        cs.set_program_output(&res2).unwrap();
+        test_program(cs, script! { 1 0 }).unwrap();
+    }
+
     #[test]
     fn test_i32_to_positive_limbs() {
         let mut prng = ChaCha20Rng::seed_from_u64(0);
@@ -383,4 +822,106 @@ mod test {
             .unwrap();
         }
     }
+
+    #[test]
+    fn test_from_positive_limbs() {
+        let mut prng = ChaCha20Rng::seed_from_u64(0);
+
+        for w in [1, 4, 7, 8] {
+            let cs = ConstraintSystem::new_ref();
+            let a: i32 = abs(prng.gen::<i32>());
+
+            let a_var = I32Var::new_program_input(&cs, a).unwrap();
+
+            let l = (31 + w - 1) / w;
+            let limbs = a_var.to_positive_limbs(l, w).unwrap();
+            let b_var = I32Var::from_positive_limbs(&limbs, w).unwrap();
+            assert_eq!(b_var.value, a);
+
+            a_var.equalverify(&b_var).unwrap();
+            cs.set_program_output(&b_var).unwrap();
+            test_program(cs, script! { { a } }).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_constant_to_positive_limbs() {
+        let mut prng = ChaCha20Rng::seed_from_u64(0);
+
+        let cs = ConstraintSystem::new_ref();
+        let a: i32 = abs(prng.gen::<i32>());
+
+        let res_var = I32Var::constant_to_positive_limbs(&cs, a, 8, 4).unwrap();
+
+        let mut expected = vec![];
+        let mut cur = a as u32;
+        for _ in 0..8 {
+            expected.push(cur & 15);
+            cur >>= 4;
+        }
+
+        assert_eq!(
+            res_var.iter().map(|v| v.value as u32).collect::<Vec<_>>(),
+            expected
+        );
+        cs.set_program_output_vec(&res_var).unwrap();
+
+        Compiler::compile(cs.clone()).unwrap();
+        assert!(!cs
+            .cost_breakdown()
+            .unwrap()
+            .contains_key("i32_to_positive_limbs_check"));
+
+        test_program(
+            cs,
+            script! {
+                { expected }
+            },
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_assert_in_set() {
+        let set = [-7, 0, 300];
+        for &v in set.iter() {
+            let cs = ConstraintSystem::new_ref();
+
+            let a = I32Var::new_program_input(&cs, v).unwrap();
+            a.assert_in_set(&set).unwrap();
+            test_program(cs, script! {}).unwrap();
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_assert_in_set_non_member() {
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_program_input(&cs, 5).unwrap();
+        a.assert_in_set(&[-7, 0, 300]).unwrap();
+    }
+
+    #[test]
+    fn test_double() {
+        let mut prng = ChaCha20Rng::seed_from_u64(0);
+
+        for _ in 0..10 {
+            let v = prng.gen_range(-(1 << 30)..(1 << 30));
+
+            let cs = ConstraintSystem::new_ref();
+            let a = I32Var::new_program_input(&cs, v).unwrap();
+            let b = a.double().unwrap();
+            let c = &a + &a;
+            assert_eq!(b.value, c.value);
+
+            cs.set_program_output(&b).unwrap();
+            cs.set_program_output(&c).unwrap();
+            test_program(cs, script! { { 2 * v } { 2 * v } }).unwrap();
+        }
+
+        let cs = ConstraintSystem::new_ref();
+        let a = I32Var::new_constant(&cs, 1 << 30).unwrap();
+        assert!(a.double().is_err());
+    }
 }
@@ -8,3 +8,12 @@ pub mod str;
 pub mod utils;
 
 pub mod bool;
+
+use crate::reference_eval::ReferenceEval;
+
+/// Returns the pure-Rust models of the gadgets that have one.
+pub(crate) fn reference_evals() -> Vec<Box<dyn ReferenceEval>> {
+    let mut references = self::i32::reference_evals();
+    references.extend(self::u8::reference_evals());
+    references
+}
@@ -1,3 +1,5 @@
+use crate::builtins::i32::I32Var;
+use crate::builtins::u8::U8Var;
 use crate::bvar::{AllocVar, AllocationMode, BVar};
 use crate::constraint_system::{ConstraintSystemRef, Element};
 use crate::options::Options;
@@ -32,6 +34,14 @@ impl BVar for StrVar {
     fn value(&self) -> Result<Self::Value> {
         Ok(self.value.clone())
     }
+
+    fn value_bytes(&self) -> Vec<u8> {
+        self.value.clone()
+    }
+
+    fn from_value_bytes(bytes: &[u8]) -> Result<Self::Value> {
+        Ok(bytes.to_vec())
+    }
 }
 
 impl AllocVar for StrVar {
@@ -57,8 +67,12 @@ impl Add for &StrVar {
         let mut res = self.value.clone();
         res.extend_from_slice(&rhs.value);
 
-        cs.insert_script(str_concatenate_gadget, vec![self.variable, rhs.variable])
-            .unwrap();
+        cs.insert_script(
+            "str_concatenate_gadget",
+            str_concatenate_gadget,
+            vec![self.variable, rhs.variable],
+        )
+        .unwrap();
 
         StrVar::new_function_output(&cs, res).unwrap()
     }
@@ -70,6 +84,7 @@ impl StrVar {
 
         let cs = self.cs();
         cs.insert_script_complex(
+            "len_equalverify_gadget",
             len_equalverify_gadget,
             self.variables(),
             &Options::new().with_u32("len", l as u32),
@@ -82,6 +97,7 @@ impl StrVar {
 
         let cs = self.cs();
         cs.insert_script_complex(
+            "len_lessthan_gadget",
             len_lessthan_gadget,
             self.variables(),
             &Options::new().with_u32("len", l as u32),
@@ -94,6 +110,7 @@ impl StrVar {
 
         let cs = self.cs();
         cs.insert_script_complex(
+            "len_lessthan_gadget",
             len_lessthan_gadget,
             self.variables(),
             &Options::new().with_u32("len", (l + 1) as u32),
@@ -102,6 +119,122 @@ impl StrVar {
     }
 }
 
+impl StrVar {
+    /// Returns the byte length of the string as an I32Var.
+    pub fn len_var(&self) -> Result<I32Var> {
+        assert!(self.value.len() <= i32::MAX as usize);
+
+        let cs = self.cs();
+        cs.insert_script("str_len_gadget", str_len_gadget, self.variables())?;
+        I32Var::new_function_output(&cs, self.value.len() as i32)
+    }
+}
+
+impl StrVar {
+    /// Splits the string into its bytes, with a gadget that checks that the bytes concatenate
+    /// back to the string.
+    pub fn to_bytes(&self) -> Result<Vec<U8Var>> {
+        let cs = self.cs();
+
+        if self.value.is_empty() {
+            self.len_equalverify(0);
+            return Ok(vec![]);
+        }
+
+        let bytes = U8Var::new_hint_vec(&cs, &self.value)?;
+        for (i, byte) in bytes.iter().enumerate() {
+            cs.describe_hint(byte.variable, format!("byte {} of {}", i, self.variable))?;
+        }
+
+        let mut variables = vec![self.variable];
+        for byte in bytes.iter() {
+            variables.push(byte.variable);
+        }
+
+        cs.insert_script_complex(
+            "str_to_bytes_check",
+            str_to_bytes_check,
+            variables,
+            &Options::new().with_u32("len", bytes.len() as u32),
+        )?;
+
+        Ok(bytes)
+    }
+
+    /// Concatenates the bytes into a string.
+    pub fn from_u8_vars(bytes: &[U8Var]) -> Result<StrVar> {
+        assert!(!bytes.is_empty());
+
+        let mut cs = bytes[0].cs();
+        for byte in bytes[1..].iter() {
+            cs = cs.try_and(&byte.cs)?;
+        }
+
+        cs.insert_script_complex(
+            "str_from_u8_vars_gadget",
+            str_from_u8_vars_gadget,
+            bytes.iter().map(|byte| byte.variable).collect::<Vec<_>>(),
+            &Options::new().with_u32("len", bytes.len() as u32),
+        )?;
+
+        StrVar::new_function_output(&cs, bytes.iter().map(|byte| byte.value).collect())
+    }
+}
+
+/// Turns a number between 0 and 255 into the string of that single byte. Up to 127, the number is
+/// already encoded as that byte, except 0, which is encoded as the empty string. Above 128, the
+/// byte is the encoding of `128 - n`, with the sign bit set. 0 and 128 (the negative zero) cannot
+/// be obtained by arithmetic and are pushed directly.
+fn u8_to_byte_str() -> Script {
+    script! {
+        OP_DUP 0 256 OP_WITHIN OP_VERIFY
+        OP_DUP OP_NOTIF
+            OP_DROP { vec![0u8] }
+        OP_ELSE
+            OP_DUP 128 OP_EQUAL
+            OP_IF
+                OP_DROP { vec![128u8] }
+            OP_ELSE
+                OP_DUP 128 OP_GREATERTHAN
+                OP_IF
+                    128 OP_SWAP OP_SUB
+                OP_ENDIF
+            OP_ENDIF
+        OP_ENDIF
+    }
+}
+
+/// Concatenates the bytes on the top of the stack, the last byte being on the top.
+fn concatenate_u8s(len: usize) -> Script {
+    script! {
+        { u8_to_byte_str() }
+        for _ in 1..len {
+            OP_SWAP
+            { u8_to_byte_str() }
+            OP_SWAP OP_CAT
+        }
+    }
+}
+
+fn str_to_bytes_check(_: &mut Stack, options: &Options) -> Result<Script> {
+    let len = options.get_u32("len")? as usize;
+    Ok(script! {
+        { concatenate_u8s(len) }
+        OP_EQUALVERIFY
+    })
+}
+
+fn str_from_u8_vars_gadget(_: &mut Stack, options: &Options) -> Result<Script> {
+    let len = options.get_u32("len")? as usize;
+    Ok(concatenate_u8s(len))
+}
+
+fn str_len_gadget() -> Script {
+    script! {
+        OP_SIZE OP_NIP
+    }
+}
+
 fn str_concatenate_gadget() -> Script {
     Script::from(vec![OP_CAT.to_u8()])
 }
@@ -119,3 +252,62 @@ fn len_lessthan_gadget(_: &mut Stack, options: &Options) -> Result<Script> {
         OP_SIZE { len } OP_LESSTHAN OP_VERIFY OP_DROP
     })
 }
+
+#[cfg(test)]
+mod test {
+    use crate::builtins::str::StrVar;
+    use crate::bvar::AllocVar;
+    use crate::constraint_system::ConstraintSystem;
+    use crate::test_program;
+    use crate::treepp::*;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha20Rng;
+
+    #[test]
+    fn test_len_var() {
+        let mut prng = ChaCha20Rng::seed_from_u64(0);
+
+        for len in [0, 1, 20, 75, 76, 255, 256, 520] {
+            let cs = ConstraintSystem::new_ref();
+
+            let value = (0..len).map(|_| prng.gen()).collect::<Vec<u8>>();
+            let a = StrVar::new_program_input(&cs, value).unwrap();
+            let l = a.len_var().unwrap();
+            assert_eq!(l.value, len);
+
+            cs.set_program_output(&l).unwrap();
+            test_program(cs, script! { { len } }).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_u8_vars_round_trip() {
+        let mut prng = ChaCha20Rng::seed_from_u64(0);
+
+        let random: [u8; 32] = prng.gen();
+        for value in [vec![0, 127, 128, 255], random.to_vec()] {
+            let cs = ConstraintSystem::new_ref();
+
+            let a = StrVar::new_program_input(&cs, value.clone()).unwrap();
+            let bytes = a.to_bytes().unwrap();
+            assert_eq!(
+                bytes.iter().map(|byte| byte.value).collect::<Vec<_>>(),
+                value
+            );
+
+            let b = StrVar::from_u8_vars(&bytes).unwrap();
+            assert_eq!(b.value, value);
+
+            cs.set_program_output(&b).unwrap();
+            cs.set_program_output(&bytes[2]).unwrap();
+            test_program(
+                cs,
+                script! {
+                    { value.clone() }
+                    { value[2] as u32 }
+                },
+            )
+            .unwrap();
+        }
+    }
+}
@@ -1,8 +1,15 @@
+use crate::builtins::bool::BoolVar;
 use crate::bvar::{AllocVar, AllocationMode, BVar};
 use crate::constraint_system::{ConstraintSystemRef, Element};
+use crate::options::Options;
+use crate::reference_eval::{nums, ReferenceEval};
+use crate::script_generator::{GadgetSpec, ScriptGenerator};
+use crate::stack::Stack;
 use crate::treepp::*;
-use anyhow::Result;
-use std::ops::{Add, Sub};
+use anyhow::{Error, Result};
+use rand::Rng;
+use rand_chacha::ChaCha20Rng;
+use std::ops::{Add, BitAnd, BitOr, BitXor, Sub};
 
 #[derive(Debug, Clone)]
 pub struct U8Var {
@@ -29,6 +36,17 @@ impl BVar for U8Var {
     fn value(&self) -> anyhow::Result<Self::Value> {
         Ok(self.value)
     }
+
+    fn value_bytes(&self) -> Vec<u8> {
+        vec![self.value]
+    }
+
+    fn from_value_bytes(bytes: &[u8]) -> Result<Self::Value> {
+        match bytes {
+            [v] => Ok(*v),
+            _ => Err(Error::msg("A u8 value must be encoded in 1 byte")),
+        }
+    }
 }
 
 impl AllocVar for U8Var {
@@ -53,8 +71,13 @@ impl Add for &U8Var {
 
         let cs = self.cs.and(&rhs.cs);
 
-        cs.insert_script(u8_add, [self.variable, rhs.variable])
-            .unwrap();
+        cs.insert_script_with_spec(
+            "u8_add",
+            u8_add,
+            [self.variable, rhs.variable],
+            GadgetSpec::commutative_binary(),
+        )
+        .unwrap();
 
         let res_var = U8Var::new_variable(&cs, res, AllocationMode::FunctionOutput).unwrap();
         res_var
@@ -67,6 +90,40 @@ fn u8_add() -> Script {
     }
 }
 
+impl U8Var {
+    /// Adds two U8Vars without overflowing, returning the low byte of the sum and the carry.
+    pub fn add_with_carry(&self, rhs: &U8Var) -> Result<(U8Var, BoolVar)> {
+        let (res, carry) = self.value.overflowing_add(rhs.value);
+
+        let cs = self.cs.try_and(&rhs.cs)?;
+        cs.insert_script_with_spec(
+            "u8_add_with_carry",
+            u8_add_with_carry,
+            [self.variable, rhs.variable],
+            GadgetSpec {
+                consumes: 2,
+                produces: 2,
+                altstack_delta: 0,
+                commutative: true,
+            },
+        )?;
+
+        let res_var = U8Var::new_function_output(&cs, res)?;
+        let carry_var = BoolVar::new_function_output(&cs, carry)?;
+        Ok((res_var, carry_var))
+    }
+}
+
+fn u8_add_with_carry() -> Script {
+    script! {
+        OP_ADD
+        OP_DUP 256 OP_GREATERTHANOREQUAL
+        OP_DUP OP_IF
+            OP_SWAP 256 OP_SUB OP_SWAP
+        OP_ENDIF
+    }
+}
+
 impl Sub for &U8Var {
     type Output = U8Var;
 
@@ -75,7 +132,7 @@ impl Sub for &U8Var {
 
         let cs = self.cs.and(&rhs.cs);
 
-        cs.insert_script(u8_sub, [self.variable, rhs.variable])
+        cs.insert_script("u8_sub", u8_sub, [self.variable, rhs.variable])
             .unwrap();
 
         let res_var = U8Var::new_variable(&cs, res, AllocationMode::FunctionOutput).unwrap();
@@ -91,10 +148,124 @@ fn u8_sub() -> Script {
 
 impl U8Var {
     pub fn check_format(&self) -> Result<()> {
-        self.cs.insert_script(u8_check_format, [self.variable])
+        self.cs
+            .insert_script("u8_check_format", u8_check_format, [self.variable])
     }
 }
 
+impl BitAnd for &U8Var {
+    type Output = U8Var;
+
+    fn bitand(self, rhs: Self) -> Self::Output {
+        self.bitwise(rhs, "u8_and", u8_and, self.value & rhs.value)
+            .unwrap()
+    }
+}
+
+impl BitOr for &U8Var {
+    type Output = U8Var;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        self.bitwise(rhs, "u8_or", u8_or, self.value | rhs.value)
+            .unwrap()
+    }
+}
+
+impl BitXor for &U8Var {
+    type Output = U8Var;
+
+    fn bitxor(self, rhs: Self) -> Self::Output {
+        self.bitwise(rhs, "u8_xor", u8_xor, self.value ^ rhs.value)
+            .unwrap()
+    }
+}
+
+impl U8Var {
+    fn bitwise(
+        &self,
+        rhs: &U8Var,
+        name: &'static str,
+        gadget: fn() -> Script,
+        res: u8,
+    ) -> Result<U8Var> {
+        let cs = self.cs.try_and(&rhs.cs)?;
+
+        self.check_format()?;
+        rhs.check_format()?;
+        cs.insert_script(name, gadget, [self.variable, rhs.variable])?;
+
+        U8Var::new_function_output(&cs, res)
+    }
+}
+
+// Tapscript disables `OP_AND`, `OP_OR`, and `OP_XOR`, so the bytes are decomposed into bits, from
+// the most significant one, and the bits are combined with the boolean opcodes.
+fn u8_bitwise(combine: Script) -> Script {
+    script! {
+        0 OP_ROT OP_ROT
+        for i in (0..8).rev() {
+            // stack: acc, a, b
+            OP_SWAP
+            OP_DUP { 1 << i } OP_GREATERTHANOREQUAL
+            OP_DUP OP_TOALTSTACK
+            OP_IF { 1 << i } OP_SUB OP_ENDIF
+            OP_SWAP
+            OP_DUP { 1 << i } OP_GREATERTHANOREQUAL
+            OP_DUP OP_TOALTSTACK
+            OP_IF { 1 << i } OP_SUB OP_ENDIF
+            OP_FROMALTSTACK OP_FROMALTSTACK
+            { combine.clone() }
+            OP_IF OP_ROT { 1 << i } OP_ADD OP_ROT OP_ROT OP_ENDIF
+        }
+        OP_2DROP
+    }
+}
+
+fn u8_and() -> Script {
+    u8_bitwise(script! { OP_BOOLAND })
+}
+
+fn u8_or() -> Script {
+    u8_bitwise(script! { OP_BOOLOR })
+}
+
+fn u8_xor() -> Script {
+    u8_bitwise(script! { OP_NUMNOTEQUAL })
+}
+
+impl U8Var {
+    /// Rotates the bits to the left by `n` positions, by repeatedly doubling the value and
+    /// wrapping the top bit around.
+    pub fn rotate_left(&self, n: u32) -> Result<U8Var> {
+        self.cs.insert_script_complex(
+            "u8_rotate_left",
+            u8_rotate_left,
+            [self.variable],
+            &Options::new().with_u32("n", n % 8),
+        )?;
+        U8Var::new_function_output(&self.cs, self.value.rotate_left(n))
+    }
+
+    /// Rotates the bits to the right by `n` positions, which is a left rotation by `8 - n`.
+    pub fn rotate_right(&self, n: u32) -> Result<U8Var> {
+        self.rotate_left(8 - n % 8)
+    }
+}
+
+fn u8_rotate_left(_: &mut Stack, options: &Options) -> Result<Script> {
+    let n = options.get_u32("n")?;
+    Ok(script! {
+        for _ in 0..n {
+            OP_DUP 128 OP_GREATERTHANOREQUAL
+            OP_IF
+                128 OP_SUB OP_DUP OP_ADD OP_1ADD
+            OP_ELSE
+                OP_DUP OP_ADD
+            OP_ENDIF
+        }
+    })
+}
+
 fn u8_check_format() -> Script {
     script! {
         OP_DUP 0 OP_GREATERTHANOREQUAL OP_VERIFY
@@ -102,13 +273,120 @@ fn u8_check_format() -> Script {
     }
 }
 
+struct U8AddReference;
+
+impl ReferenceEval for U8AddReference {
+    fn name(&self) -> &'static str {
+        "u8_add"
+    }
+
+    fn generator(&self) -> ScriptGenerator {
+        ScriptGenerator::Simple(u8_add)
+    }
+
+    fn sample(&self, prng: &mut ChaCha20Rng) -> (Vec<Element>, Options) {
+        let a = prng.gen_range(0..=255);
+        let b = prng.gen_range(0..=255 - a);
+        (vec![Element::Num(a), Element::Num(b)], Options::new())
+    }
+
+    fn eval(&self, inputs: &[Element], _: &Options) -> Result<Vec<Element>> {
+        let [a, b] = nums(inputs)?;
+        Ok(vec![Element::Num(a + b)])
+    }
+}
+
+struct U8SubReference;
+
+impl ReferenceEval for U8SubReference {
+    fn name(&self) -> &'static str {
+        "u8_sub"
+    }
+
+    fn generator(&self) -> ScriptGenerator {
+        ScriptGenerator::Simple(u8_sub)
+    }
+
+    fn sample(&self, prng: &mut ChaCha20Rng) -> (Vec<Element>, Options) {
+        let a = prng.gen_range(0..=255);
+        let b = prng.gen_range(0..=a);
+        (vec![Element::Num(a), Element::Num(b)], Options::new())
+    }
+
+    fn eval(&self, inputs: &[Element], _: &Options) -> Result<Vec<Element>> {
+        let [a, b] = nums(inputs)?;
+        Ok(vec![Element::Num(a - b)])
+    }
+}
+
+struct U8BitwiseReference(&'static str, fn() -> Script, fn(u8, u8) -> u8);
+
+impl ReferenceEval for U8BitwiseReference {
+    fn name(&self) -> &'static str {
+        self.0
+    }
+
+    fn generator(&self) -> ScriptGenerator {
+        ScriptGenerator::Simple(self.1)
+    }
+
+    fn sample(&self, prng: &mut ChaCha20Rng) -> (Vec<Element>, Options) {
+        let a = prng.gen::<u8>() as i32;
+        let b = prng.gen::<u8>() as i32;
+        (vec![Element::Num(a), Element::Num(b)], Options::new())
+    }
+
+    fn eval(&self, inputs: &[Element], _: &Options) -> Result<Vec<Element>> {
+        let [a, b] = nums(inputs)?;
+        Ok(vec![Element::Num((self.2)(a as u8, b as u8) as i32)])
+    }
+}
+
+struct U8RotateLeftReference;
+
+impl ReferenceEval for U8RotateLeftReference {
+    fn name(&self) -> &'static str {
+        "u8_rotate_left"
+    }
+
+    fn generator(&self) -> ScriptGenerator {
+        ScriptGenerator::Complex(u8_rotate_left)
+    }
+
+    fn sample(&self, prng: &mut ChaCha20Rng) -> (Vec<Element>, Options) {
+        let a = prng.gen::<u8>() as i32;
+        let n = prng.gen_range(0..8);
+        (vec![Element::Num(a)], Options::new().with_u32("n", n))
+    }
+
+    fn eval(&self, inputs: &[Element], options: &Options) -> Result<Vec<Element>> {
+        let [a] = nums(inputs)?;
+        let n = options.get_u32("n")?;
+        Ok(vec![Element::Num((a as u8).rotate_left(n) as i32)])
+    }
+}
+
+pub(crate) fn reference_evals() -> Vec<Box<dyn ReferenceEval>> {
+    vec![
+        Box::new(U8AddReference),
+        Box::new(U8SubReference),
+        Box::new(U8BitwiseReference("u8_and", u8_and, |a, b| a & b)),
+        Box::new(U8BitwiseReference("u8_or", u8_or, |a, b| a | b)),
+        Box::new(U8BitwiseReference("u8_xor", u8_xor, |a, b| a ^ b)),
+        Box::new(U8RotateLeftReference),
+    ]
+}
+
 #[cfg(test)]
 mod test {
     use crate::builtins::u8::U8Var;
     use crate::bvar::{AllocVar, AllocationMode};
+    use crate::compiler::{Compiler, CompilerOptions};
     use crate::constraint_system::{ConstraintSystem, Element};
     use crate::test_program;
     use crate::treepp::*;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha20Rng;
 
     #[test]
     fn test_add_u8() {
@@ -120,9 +398,39 @@ mod test {
         let c = &a + &b;
         c.check_format().unwrap();
         cs.set_program_output(&c).unwrap();
+        // the spec of the addition agrees with its script
+        Compiler::compile_with_options(cs.clone(), &CompilerOptions::new().with_strict(true))
+            .unwrap();
         test_program(cs, script! { 12 }).unwrap();
     }
 
+    #[test]
+    fn test_add_with_carry() {
+        for (a, b) in [(255, 1), (200, 100), (100, 27)] {
+            let cs = ConstraintSystem::new_ref();
+
+            let a_var = U8Var::new_program_input(&cs, a).unwrap();
+            let b_var = U8Var::new_constant(&cs, b).unwrap();
+
+            let (c, carry) = a_var.add_with_carry(&b_var).unwrap();
+            let sum = a as u32 + b as u32;
+            assert_eq!(c.value as u32, sum & 0xff);
+            assert_eq!(carry.value, sum >= 256);
+
+            c.check_format().unwrap();
+            cs.set_program_output(&c).unwrap();
+            cs.set_program_output(&carry).unwrap();
+            test_program(
+                cs,
+                script! {
+                    { sum & 0xff }
+                    { (sum >= 256) as u32 }
+                },
+            )
+            .unwrap();
+        }
+    }
+
     #[test]
     fn test_sub_u8() {
         let cs = ConstraintSystem::new_ref();
@@ -178,4 +486,74 @@ mod test {
         a.check_format().unwrap();
         test_program(cs, script! {}).unwrap();
     }
+
+    #[test]
+    fn test_rotate() {
+        let mut prng = ChaCha20Rng::seed_from_u64(0);
+
+        let mut values = vec![0u8, 1, 0x80, 0xa5, 0xff];
+        for _ in 0..5 {
+            values.push(prng.gen());
+        }
+
+        for &value in values.iter() {
+            for n in 0..8 {
+                let cs = ConstraintSystem::new_ref();
+
+                let a = U8Var::new_program_input(&cs, value).unwrap();
+                let b = a.rotate_left(n).unwrap();
+                let c = a.rotate_right(n).unwrap();
+                assert_eq!(b.value, value.rotate_left(n));
+                assert_eq!(c.value, value.rotate_right(n));
+
+                cs.set_program_output(&b).unwrap();
+                cs.set_program_output(&c).unwrap();
+                test_program(
+                    cs,
+                    script! {
+                        { value.rotate_left(n) as u32 }
+                        { value.rotate_right(n) as u32 }
+                    },
+                )
+                .unwrap();
+            }
+        }
+    }
+
+    #[test]
+    fn test_bitwise() {
+        let mut prng = ChaCha20Rng::seed_from_u64(0);
+
+        let mut pairs = vec![(0u8, 0u8), (0xff, 0x00), (0xff, 0xff), (0xa5, 0x5a)];
+        for _ in 0..20 {
+            pairs.push((prng.gen(), prng.gen()));
+        }
+
+        for &(a_val, b_val) in pairs.iter() {
+            let cs = ConstraintSystem::new_ref();
+
+            let a = U8Var::new_program_input(&cs, a_val).unwrap();
+            let b = U8Var::new_program_input(&cs, b_val).unwrap();
+
+            let and = &a & &b;
+            let or = &a | &b;
+            let xor = &a ^ &b;
+            assert_eq!(and.value, a_val & b_val);
+            assert_eq!(or.value, a_val | b_val);
+            assert_eq!(xor.value, a_val ^ b_val);
+
+            cs.set_program_output(&and).unwrap();
+            cs.set_program_output(&or).unwrap();
+            cs.set_program_output(&xor).unwrap();
+            test_program(
+                cs,
+                script! {
+                    { (a_val & b_val) as u32 }
+                    { (a_val | b_val) as u32 }
+                    { (a_val ^ b_val) as u32 }
+                },
+            )
+            .unwrap();
+        }
+    }
 }
@@ -24,12 +24,20 @@ pub trait BVar: Clone {
     /// `ConstraintSystem`.
     fn value(&self) -> Result<Self::Value>;
 
+    /// Returns the canonical byte encoding of the value: each numeric element is encoded in
+    /// little-endian, and each string element is kept as it is.
+    fn value_bytes(&self) -> Vec<u8>;
+
+    /// Recovers the value from its canonical byte encoding.
+    fn from_value_bytes(bytes: &[u8]) -> Result<Self::Value>;
+
     fn equalverify(&self, rhs: &Self) -> Result<()> {
         assert_eq!(self.value()?, rhs.value()?);
-        let cs = self.cs().and(&rhs.cs());
+        let cs = self.cs().try_and(&rhs.cs())?;
 
         for (&self_var, &rhs_var) in self.variables().iter().zip(rhs.variables().iter()) {
             cs.insert_script(
+                "single_elem_equalverify",
                 single_elem_equalverify as fn() -> Script,
                 [self_var, rhs_var],
             )?;
@@ -39,6 +47,33 @@ pub trait BVar: Clone {
     }
 }
 
+/// An object-safe counterpart of `BVar`, which allows variables of different types to be stored
+/// in the same collection.
+pub trait ErasedBVar {
+    /// Returns the underlying `ConstraintSystemRef`.
+    fn cs(&self) -> ConstraintSystemRef;
+
+    /// Returns the assigned stack elements indices.
+    fn variables(&self) -> Vec<usize>;
+
+    /// Returns the canonical byte encoding of the value.
+    fn value_bytes(&self) -> Vec<u8>;
+}
+
+impl<T: BVar> ErasedBVar for T {
+    fn cs(&self) -> ConstraintSystemRef {
+        BVar::cs(self)
+    }
+
+    fn variables(&self) -> Vec<usize> {
+        BVar::variables(self)
+    }
+
+    fn value_bytes(&self) -> Vec<u8> {
+        BVar::value_bytes(self)
+    }
+}
+
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum AllocationMode {
     ProgramInput,
@@ -70,13 +105,50 @@ pub trait AllocVar: BVar + Sized {
         Self::new_variable(cs, data, AllocationMode::Hint)
     }
 
+    fn new_constant_vec(
+        cs: &ConstraintSystemRef,
+        data: &[<Self as BVar>::Value],
+    ) -> Result<Vec<Self>> {
+        data.iter()
+            .map(|v| Self::new_constant(cs, v.clone()))
+            .collect()
+    }
+
+    fn new_program_input_vec(
+        cs: &ConstraintSystemRef,
+        data: &[<Self as BVar>::Value],
+    ) -> Result<Vec<Self>> {
+        data.iter()
+            .map(|v| Self::new_program_input(cs, v.clone()))
+            .collect()
+    }
+
+    fn new_hint_vec(cs: &ConstraintSystemRef, data: &[<Self as BVar>::Value]) -> Result<Vec<Self>> {
+        data.iter().map(|v| Self::new_hint(cs, v.clone())).collect()
+    }
+
     fn copy(&self) -> Result<Self> {
         let cs = self.cs();
-        cs.insert_script(dummy_script, self.variables())?;
+        cs.insert_script("dummy_script", dummy_script, self.variables())?;
         Self::new_function_output(&cs, self.value()?)
     }
 }
 
+/// Verifies that two slices of variables are equal element-wise.
+pub fn equalverify_slice<T: BVar>(a: &[T], b: &[T]) -> Result<()> {
+    assert_eq!(a.len(), b.len());
+    for (a, b) in a.iter().zip(b.iter()) {
+        a.equalverify(b)?;
+    }
+    Ok(())
+}
+
+/// Folds the variables into an accumulator from the left, which captures the loops of a
+/// verifier, such as hashing a chain.
+pub fn fold<T: BVar, A>(init: A, items: &[T], f: impl Fn(A, &T) -> A) -> A {
+    items.iter().fold(init, f)
+}
+
 pub(crate) fn dummy_script() -> Script {
     script! {}
 }
@@ -84,3 +156,135 @@ pub(crate) fn dummy_script() -> Script {
 fn single_elem_equalverify() -> Script {
     Script::from(vec![OP_EQUALVERIFY.to_u8()])
 }
+
+#[cfg(test)]
+mod test {
+    use crate::builtins::bool::BoolVar;
+    use crate::builtins::hash::HashVar;
+    use crate::builtins::i32::I32Var;
+    use crate::builtins::str::StrVar;
+    use crate::builtins::u8::U8Var;
+    use crate::bvar::{equalverify_slice, AllocVar, BVar, ErasedBVar};
+    use crate::constraint_system::ConstraintSystem;
+    use crate::test_program;
+    use crate::treepp::*;
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha20Rng;
+
+    fn check_value_bytes_round_trip<T: AllocVar>(var: &T) {
+        let bytes = BVar::value_bytes(var);
+        assert_eq!(T::from_value_bytes(&bytes).unwrap(), var.value().unwrap());
+    }
+
+    #[test]
+    fn test_value_bytes_round_trip() {
+        let mut prng = ChaCha20Rng::seed_from_u64(0);
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_constant(&cs, -prng.gen_range(0..i32::MAX)).unwrap();
+        assert_eq!(BVar::value_bytes(&a), a.value.to_le_bytes().to_vec());
+        check_value_bytes_round_trip(&a);
+
+        let b = U8Var::new_constant(&cs, prng.gen()).unwrap();
+        check_value_bytes_round_trip(&b);
+
+        let c = BoolVar::new_constant(&cs, true).unwrap();
+        check_value_bytes_round_trip(&c);
+
+        let d_val: [u8; 20] = prng.gen();
+        let d = StrVar::new_constant(&cs, d_val.to_vec()).unwrap();
+        check_value_bytes_round_trip(&d);
+
+        let e_val: [u8; 32] = prng.gen();
+        let e = HashVar::new_constant(&cs, e_val.to_vec()).unwrap();
+        assert_eq!(BVar::value_bytes(&e), e_val.to_vec());
+        check_value_bytes_round_trip(&e);
+
+        assert!(I32Var::from_value_bytes(&[0u8; 3]).is_err());
+        assert!(U8Var::from_value_bytes(&[]).is_err());
+        assert!(BoolVar::from_value_bytes(&[2]).is_err());
+    }
+
+    #[test]
+    fn test_equalverify_slice() {
+        let cs = ConstraintSystem::new_ref();
+
+        let values = [3, -7, 100];
+        let a = values
+            .iter()
+            .map(|&v| I32Var::new_constant(&cs, v).unwrap())
+            .collect::<Vec<_>>();
+        let b = values
+            .iter()
+            .map(|&v| I32Var::new_hint(&cs, v).unwrap())
+            .collect::<Vec<_>>();
+
+        equalverify_slice(&a, &b).unwrap();
+        test_program(cs, script! {}).unwrap();
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_equalverify_slice_different_lengths() {
+        let cs = ConstraintSystem::new_ref();
+
+        let a = vec![I32Var::new_constant(&cs, 1).unwrap()];
+        let b = vec![
+            I32Var::new_constant(&cs, 1).unwrap(),
+            I32Var::new_constant(&cs, 2).unwrap(),
+        ];
+        equalverify_slice(&a, &b).unwrap();
+    }
+
+    #[test]
+    fn test_erased_bvar() {
+        let mut prng = ChaCha20Rng::seed_from_u64(0);
+        let cs = ConstraintSystem::new_ref();
+
+        let hash_val: [u8; 32] = prng.gen();
+
+        let vars: Vec<Box<dyn ErasedBVar>> = vec![
+            Box::new(I32Var::new_constant(&cs, 5).unwrap()),
+            Box::new(HashVar::new_constant(&cs, hash_val.to_vec()).unwrap()),
+        ];
+
+        for var in vars.iter() {
+            cs.set_program_output(var.as_ref()).unwrap();
+        }
+        assert_eq!(vars[1].value_bytes(), hash_val.to_vec());
+
+        test_program(
+            cs,
+            script! {
+                5
+                { hash_val.to_vec() }
+            },
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_alloc_vec() {
+        let mut prng = ChaCha20Rng::seed_from_u64(0);
+        let cs = ConstraintSystem::new_ref();
+
+        let values = (0..16).map(|_| prng.gen()).collect::<Vec<u8>>();
+        let inputs = U8Var::new_program_input_vec(&cs, &values[..4]).unwrap();
+        let constants = U8Var::new_constant_vec(&cs, &values[4..10]).unwrap();
+        let hints = U8Var::new_hint_vec(&cs, &values[10..]).unwrap();
+
+        let vars = [inputs, constants, hints].concat();
+        assert_eq!(vars.iter().map(|var| var.value).collect::<Vec<_>>(), values);
+        cs.set_program_output_vec(&vars).unwrap();
+
+        test_program(
+            cs,
+            script! {
+                for value in values.iter() {
+                    { *value as u32 }
+                }
+            },
+        )
+        .unwrap();
+    }
+}
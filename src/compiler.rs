@@ -1,21 +1,478 @@
-use crate::constraint_system::{ConstraintSystemRef, Element, TraceEntry};
+use crate::builtins::hash::bitcoin_num_to_bytes;
+use crate::constraint_system::{ConstraintSystem, ConstraintSystemRef, Element, TraceEntry};
+use crate::execute_tapscript;
 use crate::stack::Stack;
 use crate::treepp::*;
-use anyhow::Result;
-use bitcoin::opcodes::Ordinary::{OP_1SUB, OP_2DROP, OP_DEPTH, OP_DROP, OP_FROMALTSTACK, OP_ROLL};
-use bitcoin::ScriptBuf;
+use anyhow::{Error, Result};
+use bitcoin::opcodes::all::{OP_CAT, OP_PUSHBYTES_0, OP_PUSHNUM_1, OP_PUSHNUM_NEG1};
+use bitcoin::opcodes::Ordinary::{
+    OP_1SUB, OP_2DROP, OP_DEPTH, OP_DROP, OP_ELSE, OP_ENDIF, OP_FROMALTSTACK, OP_IF, OP_NIP,
+    OP_NOTIF, OP_ROLL, OP_TOALTSTACK,
+};
+use bitcoin::opcodes::{Class, ClassifyContext};
+use bitcoin::script::Instruction;
+use bitcoin::taproot::ControlBlock;
+use bitcoin::{ScriptBuf, Witness};
+use bitcoin_scriptexec::ExecError;
+use std::io::{ErrorKind, Read, Write};
 
 pub struct CompiledProgram {
     pub input: Vec<Element>,
     pub hint: Vec<Element>,
+    /// The descriptions of the hints that are given with `ConstraintSystemRef::describe_hint`, in
+    /// the same order as the hints in the witness. They are kept even when the hints are streamed.
+    pub hint_descriptions: Vec<Option<String>>,
     pub script: Script,
+    /// The number of elements left below the outputs, which is nonzero only if the cleanup is
+    /// left to the caller.
+    pub num_residual_elements: usize,
+    /// The number of outputs left on the top of the stack.
+    pub num_outputs: usize,
+    /// Whether any of the gadgets uses `OP_CAT`, in which case the program can only be executed
+    /// with `OP_CAT` enabled.
+    pub requires_op_cat: bool,
+    /// The maximum number of elements in the stack and the altstack combined, between two trace
+    /// entries and once the outputs are staged, including the hints that have not been used yet.
+    /// The elements that a gadget only uses temporarily are not counted, so the actual peak during
+    /// the execution may be higher.
+    pub max_stack_depth: usize,
+    /// The memory indices of the outputs, in the order they are left on the stack, from the bottom
+    /// to the top. By default, this is the order in which the outputs are declared with
+    /// `set_program_output`, and with `CompilerOptions::reorder_outputs`, the outputs are sorted
+    /// by their memory indices instead.
+    pub output_order: Vec<usize>,
+    /// The values of the outputs, in the same order as `output_order`.
+    pub output: Vec<Element>,
+    /// Whether the script has been written to a sink by `Compiler::compile_streaming`, in which
+    /// case `script` is empty and the methods that need the script fail.
+    pub script_streamed: bool,
+    /// Whether the hints have been written to a writer by `Compiler::compile_with_hint_writer`,
+    /// in which case `hint` is empty and the methods that need the witness fail.
+    pub hints_streamed: bool,
+}
+
+impl CompiledProgram {
+    /// Returns the witness of the program in the spending order, which consists of the hints
+    /// followed by the program inputs. It fails if the hints have been streamed, since they are
+    /// not retained in the program.
+    pub fn to_witness(&self) -> Result<Vec<Vec<u8>>> {
+        if self.hints_streamed {
+            return Err(Error::msg(
+                "The hints have been streamed to a writer and are not retained in the program",
+            ));
+        }
+
+        Ok(self
+            .hint
+            .iter()
+            .chain(self.input.iter())
+            .map(Element::to_bytes)
+            .collect())
+    }
+
+    /// Composes two programs sequentially, where the outputs of `self` are the program inputs of
+    /// `other`. The hints of `other` are placed in the witness right above those of `self`, so
+    /// that they are at the bottom of the stack by the time `other` starts to pull them.
+    ///
+    /// `self` must leave no residual elements below its outputs, and neither the script nor the
+    /// hints of either program may have been streamed. The composed program reports the outputs
+    /// of `other`, whose `output_order` refers to the memory of `other`.
+    pub fn then(self, other: CompiledProgram) -> Result<CompiledProgram> {
+        self.check_script_retained()?;
+        other.check_script_retained()?;
+        if self.hints_streamed || other.hints_streamed {
+            return Err(Error::msg(
+                "The hints have been streamed to a writer and cannot be composed",
+            ));
+        }
+
+        if self.num_residual_elements != 0 {
+            return Err(Error::msg(format!(
+                "The first program leaves {} residual elements below its outputs",
+                self.num_residual_elements
+            )));
+        }
+        if self.output != other.input {
+            return Err(Error::msg(format!(
+                "The first program outputs {:?}, but the second program takes {:?} as inputs",
+                self.output, other.input
+            )));
+        }
+
+        let mut script = self.script.to_bytes();
+        script.extend_from_slice(other.script.as_bytes());
+
+        Ok(CompiledProgram {
+            input: self.input,
+            hint: [self.hint, other.hint.clone()].concat(),
+            hint_descriptions: [self.hint_descriptions, other.hint_descriptions].concat(),
+            script: ScriptBuf::from_bytes(script),
+            num_residual_elements: other.num_residual_elements,
+            num_outputs: other.num_outputs,
+            requires_op_cat: self.requires_op_cat || other.requires_op_cat,
+            max_stack_depth: (self.max_stack_depth + other.hint.len()).max(other.max_stack_depth),
+            output_order: other.output_order,
+            output: other.output,
+            script_streamed: false,
+            hints_streamed: false,
+        })
+    }
+
+    /// Returns the final witness of a spend of the program as a tapscript leaf, which can be set
+    /// as the `final_script_witness` of a PSBT input. It consists of the elements of `to_witness`,
+    /// followed by the script and the control block of the leaf. It fails if the script has been
+    /// streamed.
+    pub fn to_psbt_input_witness(&self, control_block: &ControlBlock) -> Result<Witness> {
+        self.check_script_retained()?;
+
+        let mut witness = Witness::from_slice(&self.to_witness()?);
+        witness.push(self.script.as_bytes());
+        witness.push(control_block.serialize());
+        Ok(witness)
+    }
+
+    /// Parses the script into its sequence of instructions, which are either opcodes or data
+    /// pushes.
+    pub fn opcodes(&self) -> Result<Vec<Instruction<'_>>> {
+        self.check_script_retained()?;
+
+        Ok(self
+            .script
+            .instructions()
+            .collect::<std::result::Result<Vec<_>, _>>()?)
+    }
+
+    /// Checks that the program can be relayed and spent as a tapscript leaf: it has no opcode that
+    /// is disabled or an `OP_SUCCESS` in tapscript (which includes `OP_CAT`), and neither the
+    /// pushes in the script nor the witness elements exceed `MAX_SCRIPT_ELEMENT_SIZE` bytes.
+    ///
+    /// It also checks that the script fits in `MAX_STANDARD_SCRIPT_SIZE` bytes, which is a
+    /// conservative cap carried over from legacy scripts rather than a tapscript rule, since
+    /// BIP342 removed the script size limit.
+    pub fn check_standardness(&self) -> Result<()> {
+        self.check_script_retained()?;
+
+        if self.script.len() > MAX_STANDARD_SCRIPT_SIZE {
+            return Err(Error::msg(format!(
+                "The script has {} bytes, which exceeds the limit of {}",
+                self.script.len(),
+                MAX_STANDARD_SCRIPT_SIZE
+            )));
+        }
+
+        for instruction in self.script.instruction_indices() {
+            match instruction? {
+                (pos, Instruction::Op(op)) => match op.classify(ClassifyContext::TapScript) {
+                    Class::SuccessOp | Class::IllegalOp | Class::ReturnOp => {
+                        return Err(Error::msg(format!(
+                            "The script uses {} at byte {}, which is not allowed in tapscript",
+                            op, pos
+                        )));
+                    }
+                    _ => {}
+                },
+                (pos, Instruction::PushBytes(bytes)) => {
+                    if bytes.len() > MAX_SCRIPT_ELEMENT_SIZE {
+                        return Err(Error::msg(format!(
+                            "The script pushes {} bytes at byte {}, which exceeds the limit of {}",
+                            bytes.len(),
+                            pos,
+                            MAX_SCRIPT_ELEMENT_SIZE
+                        )));
+                    }
+                }
+            }
+        }
+
+        for (i, elem) in self.to_witness()?.iter().enumerate() {
+            if elem.len() > MAX_SCRIPT_ELEMENT_SIZE {
+                return Err(Error::msg(format!(
+                    "The witness element {} has {} bytes, which exceeds the limit of {}",
+                    i,
+                    elem.len(),
+                    MAX_SCRIPT_ELEMENT_SIZE
+                )));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn check_script_retained(&self) -> Result<()> {
+        if self.script_streamed {
+            return Err(Error::msg(
+                "The script has been streamed to a sink and is not retained in the program",
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The stack that the compiler predicts right after a trace entry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TraceSnapshot {
+    /// The index of the trace entry.
+    pub trace_idx: usize,
+    /// The length of the script up to the end of the trace entry.
+    pub script_offset: usize,
+    /// The predicted stack elements, from the bottom to the top.
+    pub stack: Vec<Vec<u8>>,
+}
+
+/// Executes the program up to each snapshot and returns the trace index of the first snapshot
+/// whose predicted stack differs from the actual one.
+pub fn find_first_divergence(
+    program: &CompiledProgram,
+    snapshots: &[TraceSnapshot],
+) -> Result<Option<usize>> {
+    let script = program.script.as_bytes();
+    for snapshot in snapshots.iter() {
+        let actual_stack =
+            execute_prefix(&script[..snapshot.script_offset], program.to_witness()?)?;
+        if actual_stack != snapshot.stack {
+            return Ok(Some(snapshot.trace_idx));
+        }
+    }
+    Ok(None)
+}
+
+fn execute_prefix(script: &[u8], witness: Vec<Vec<u8>>) -> Result<Vec<Vec<u8>>> {
+    let exec = execute_tapscript(ScriptBuf::from_bytes(script.to_vec()), witness, true)?;
+    match exec.result().and_then(|res| res.error.as_ref()) {
+        // a prefix of the program does not leave a single true element on the stack
+        None | Some(ExecError::CleanStack) | Some(ExecError::EvalFalse) => {
+            Ok(exec.stack().iter_str().collect())
+        }
+        Some(e) => Err(Error::msg(format!("The execution fails: {:?}", e))),
+    }
+}
+
+/// The strategy to clear the stack elements that remain after the outputs are set aside.
+///
+/// Tapscript has no loops, so an `OP_DEPTH`-driven cleanup would still be unrolled, and it cannot
+/// beat `OP_2DROP`, which removes two elements per byte. There is therefore no crossover point,
+/// and the only alternative to dropping is to leave the cleanup to the caller.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CleanupStrategy {
+    /// Drop the remaining elements with `OP_2DROP` and `OP_DROP`.
+    #[default]
+    Drop,
+    /// Keep the remaining elements below the outputs and report their number in
+    /// `CompiledProgram::num_residual_elements`.
+    Caller,
+}
+
+/// The maximal number of elements in the stack and the altstack combined in tapscript.
+pub const MAX_STACK_SIZE: usize = 1000;
+
+/// The size limit of legacy and segwit v0 scripts. BIP342 removed it for tapscript, and it is
+/// only kept as a conservative policy cap by `CompiledProgram::check_standardness`.
+pub const MAX_STANDARD_SCRIPT_SIZE: usize = 10000;
+
+/// The maximal size of a stack element in bytes.
+pub const MAX_SCRIPT_ELEMENT_SIZE: usize = 520;
+
+#[derive(Clone, Debug)]
+pub struct CompilerOptions {
+    pub cleanup: CleanupStrategy,
+    /// Stage the outputs in the order of their positions in the stack rather than in the order
+    /// they are declared, which minimizes the pick/roll distances. The outputs then end up on the
+    /// stack in ascending order of their memory indices (the largest index on the top), instead
+    /// of the declaration order.
+    pub reorder_outputs: bool,
+    /// The maximal number of elements in the stack and the altstack combined that the program may
+    /// reach, as measured by `CompiledProgram::max_stack_depth`, beyond which the compilation
+    /// fails rather than producing a script that fails at runtime. Since the elements that a
+    /// gadget only uses temporarily are not counted, a gadget that uses many of them may still
+    /// exceed the limit at runtime. It defaults to `MAX_STACK_SIZE`.
+    pub max_stack: usize,
+    /// Whether the gadgets may use `OP_CAT`. If not, the compilation fails at the first gadget
+    /// that uses it, so that a program meant for a chain without `OP_CAT` is rejected early
+    /// instead of failing at runtime. It defaults to `true`.
+    pub op_cat: bool,
+    /// Whether to reject programs that are most likely mistakes, rather than compiling them as
+    /// they are: a program without any output, or with hints that no gadget uses and that are not
+    /// outputs, which only take up witness space and can be set to anything.
+    ///
+    /// It also executes the script of every gadget that has a `GadgetSpec` on the stack that the
+    /// compiler predicts, to check that it leaves as many elements as the spec declares. This is
+    /// skipped for the gadgets with a negative `altstack_delta`, since the elements that they take
+    /// from the altstack are not known to the compiler. It defaults to `false`.
+    pub strict: bool,
+}
+
+impl Default for CompilerOptions {
+    fn default() -> Self {
+        CompilerOptions {
+            cleanup: CleanupStrategy::default(),
+            reorder_outputs: false,
+            max_stack: MAX_STACK_SIZE,
+            op_cat: true,
+            strict: false,
+        }
+    }
+}
+
+impl CompilerOptions {
+    pub fn new() -> CompilerOptions {
+        CompilerOptions::default()
+    }
+
+    pub fn with_cleanup(mut self, cleanup: CleanupStrategy) -> CompilerOptions {
+        self.cleanup = cleanup;
+        self
+    }
+
+    pub fn with_reorder_outputs(mut self, reorder_outputs: bool) -> CompilerOptions {
+        self.reorder_outputs = reorder_outputs;
+        self
+    }
+
+    pub fn with_max_stack(mut self, max_stack: usize) -> CompilerOptions {
+        self.max_stack = max_stack;
+        self
+    }
+
+    pub fn with_op_cat(mut self, op_cat: bool) -> CompilerOptions {
+        self.op_cat = op_cat;
+        self
+    }
+
+    pub fn with_strict(mut self, strict: bool) -> CompilerOptions {
+        self.strict = strict;
+        self
+    }
 }
 
 pub struct Compiler;
 
 impl Compiler {
     pub fn compile(cs: ConstraintSystemRef) -> Result<CompiledProgram> {
-        let cs = cs.0.borrow_mut();
+        Self::compile_with_options(cs, &CompilerOptions::new())
+    }
+
+    pub fn compile_with_options(
+        cs: ConstraintSystemRef,
+        options: &CompilerOptions,
+    ) -> Result<CompiledProgram> {
+        let mut hint = vec![];
+        let mut program = Self::compile_internal(
+            cs,
+            options,
+            &mut |elem: &Element| {
+                hint.push(elem.clone());
+                Ok(())
+            },
+            None,
+            None,
+        )?;
+        program.hint = hint;
+        Ok(program)
+    }
+
+    /// Compiles the program into a hint loader script, which only pushes the hints, and the body
+    /// script, which is the rest of the program.
+    ///
+    /// The hint loader must be executed before the program inputs are placed on the stack, so
+    /// that the hints end up below the inputs, as they would be in the witness.
+    pub fn compile_split(cs: ConstraintSystemRef) -> Result<(Script, Script)> {
+        let program = Self::compile(cs)?;
+
+        let mut hint_loader = Vec::<u8>::new();
+        for elem in program.hint.iter() {
+            hint_loader.extend_from_slice(script! { { elem } }.as_bytes());
+        }
+
+        Ok((ScriptBuf::from_bytes(hint_loader), program.script))
+    }
+
+    /// Compiles the program while streaming the hints to `writer` instead of retaining them in
+    /// the returned `CompiledProgram`, whose `hint` is left empty and which is marked as
+    /// `hints_streamed`. The values of the hints remain in the memory of the constraint system,
+    /// so this only saves the copy that the returned program would hold, and does not lower the
+    /// peak memory use of the compilation.
+    ///
+    /// Each hint is written as its witness encoding, prefixed by its length as a little-endian
+    /// `u32`. The hints can be read back with `read_streamed_hints`.
+    pub fn compile_with_hint_writer<W: Write>(
+        cs: ConstraintSystemRef,
+        options: &CompilerOptions,
+        writer: &mut W,
+    ) -> Result<CompiledProgram> {
+        let mut program = Self::compile_internal(
+            cs,
+            options,
+            &mut |elem: &Element| {
+                let bytes = elem.to_bytes();
+                writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+                writer.write_all(&bytes)?;
+                Ok(())
+            },
+            None,
+            None,
+        )?;
+        program.hints_streamed = true;
+        Ok(program)
+    }
+
+    /// Compiles the program while writing the script to `sink` as it is generated, instead of
+    /// retaining it in the returned `CompiledProgram`, whose `script` is left empty and which is
+    /// marked as `script_streamed`. Only the script of the current trace entry, or of the final
+    /// output staging and cleanup, is buffered.
+    pub fn compile_streaming<W: Write>(
+        cs: ConstraintSystemRef,
+        options: &CompilerOptions,
+        sink: &mut W,
+    ) -> Result<CompiledProgram> {
+        let mut hint = vec![];
+        let mut program = Self::compile_internal(
+            cs,
+            options,
+            &mut |elem: &Element| {
+                hint.push(elem.clone());
+                Ok(())
+            },
+            Some(sink),
+            None,
+        )?;
+        program.hint = hint;
+        program.script_streamed = true;
+        Ok(program)
+    }
+
+    /// Compiles the program and also returns the stack predicted after each trace entry, so that
+    /// a debugger can compare it against the actual stack during execution.
+    ///
+    /// A gadget and the function outputs declared right after it share a single snapshot, which
+    /// is attributed to the gadget.
+    pub fn compile_and_trace(
+        cs: ConstraintSystemRef,
+    ) -> Result<(CompiledProgram, Vec<TraceSnapshot>)> {
+        let mut hint = vec![];
+        let mut snapshots = vec![];
+        let mut program = Self::compile_internal(
+            cs,
+            &CompilerOptions::new(),
+            &mut |elem: &Element| {
+                hint.push(elem.clone());
+                Ok(())
+            },
+            None,
+            Some(&mut snapshots),
+        )?;
+        program.hint = hint;
+        Ok((program, snapshots))
+    }
+
+    fn compile_internal(
+        cs: ConstraintSystemRef,
+        options: &CompilerOptions,
+        hint_sink: &mut dyn FnMut(&Element) -> Result<()>,
+        mut script_sink: Option<&mut dyn Write>,
+        mut snapshots: Option<&mut Vec<TraceSnapshot>>,
+    ) -> Result<CompiledProgram> {
+        let mut cs = cs.0.borrow_mut();
+        cs.run_pending_hooks()?;
 
         // step 1: count the last visit of all the memory entries
         let num_memory_entries = cs.memory_last_idx;
@@ -24,7 +481,7 @@ impl Compiler {
         let mut cur_time = 0;
         for trace_entry in cs.trace.iter() {
             match trace_entry {
-                TraceEntry::InsertScript(_, inputs, _) => {
+                TraceEntry::InsertScript(_, _, inputs, ..) => {
                     for &i in inputs.iter() {
                         last_visit[i] = cur_time;
                     }
@@ -64,15 +521,112 @@ impl Compiler {
             }
         }
 
+        if options.strict {
+            if output.is_empty() {
+                return Err(Error::msg(
+                    "The program has no outputs, and everything on the stack would be dropped",
+                ));
+            }
+
+            let unused_hints = cs
+                .trace
+                .iter()
+                .filter_map(|entry| match entry {
+                    TraceEntry::RequestHint(idx, _)
+                        if last_visit[*idx] < 0 && !output.contains(idx) =>
+                    {
+                        Some(*idx)
+                    }
+                    _ => None,
+                })
+                .collect::<Vec<_>>();
+            if !unused_hints.is_empty() {
+                return Err(Error::msg(format!(
+                    "The hints with memory indices {:?} are never used",
+                    unused_hints
+                )));
+            }
+        }
+
         // step 5: generate the script
         let mut script = Vec::<u8>::new();
-        let mut hint = Vec::<Element>::new();
+        // the number of bytes that have been written to the script sink, if any
+        let mut num_flushed_bytes = 0;
+        // the net number of elements that the script moves to the altstack, counted as the script
+        // is flushed
+        let mut altstack_balance = 0;
 
         let mut cur_time = 0;
+        let mut gadget_script_lengths = vec![];
+        let mut requires_op_cat = false;
 
-        for trace_entry in cs.trace.iter() {
+        // the hints that have not been pulled yet stay at the bottom of the stack
+        let all_hints = cs
+            .trace
+            .iter()
+            .filter_map(|entry| match entry {
+                TraceEntry::RequestHint(idx, _) => Some(*idx),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let hint_descriptions = cs
+            .trace
+            .iter()
+            .filter_map(|entry| match entry {
+                TraceEntry::RequestHint(_, description) => Some(description.clone()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        let mut num_pulled_hints = 0;
+        let mut max_stack_depth = all_hints.len() + input.len();
+        if max_stack_depth > options.max_stack {
+            return Err(Error::msg(format!(
+                "The witness has {} elements, which exceeds the stack limit of {}",
+                max_stack_depth, options.max_stack
+            )));
+        }
+        let mut last_gadget_idx = 0;
+
+        for (trace_idx, trace_entry) in cs.trace.iter().enumerate() {
             match trace_entry {
-                TraceEntry::InsertScript(script_generator, inputs, options) => {
+                TraceEntry::InsertScript(
+                    name,
+                    script_generator,
+                    inputs,
+                    gadget_options,
+                    spec,
+                    _,
+                ) => {
+                    if let Some(spec) = spec {
+                        let num_outputs = cs.trace[trace_idx + 1..]
+                            .iter()
+                            .take_while(|entry| matches!(entry, TraceEntry::DeclareOutput(_)))
+                            .count();
+                        if spec.consumes != inputs.len() || spec.produces != num_outputs {
+                            return Err(Error::msg(format!(
+                                "The gadget at trace entry {} declares to consume {} and produce {} elements, but it consumes {} and produces {} elements",
+                                trace_idx, spec.consumes, spec.produces, inputs.len(), num_outputs
+                            )));
+                        }
+                    }
+
+                    let mut inputs = inputs.clone();
+                    if spec.is_some_and(|spec| spec.commutative)
+                        && inputs.len() == 2
+                        && inputs[0] != inputs[1]
+                    {
+                        // whether an input is rolled does not depend on the order, since the two
+                        // inputs are different
+                        let roll =
+                            |idx: usize| last_visit[idx] == cur_time && !output.contains(&idx);
+                        let (a, b) = (inputs[0], inputs[1]);
+                        if two_inputs_staging_len(&mut stack, b, a, roll(b), roll(a))?
+                            < two_inputs_staging_len(&mut stack, a, b, roll(a), roll(b))?
+                        {
+                            inputs.swap(0, 1);
+                        }
+                    }
+
                     for (i, &input_idx) in inputs.iter().enumerate() {
                         let pos = stack.get_relative_position(input_idx)?;
                         let distance = pos + i;
@@ -90,8 +644,77 @@ impl Compiler {
                         }
                     }
 
-                    script
-                        .extend_from_slice(script_generator.run(&mut stack, &options)?.as_bytes());
+                    let gadget_script = script_generator.run(&mut stack, gadget_options)?;
+                    gadget_script_lengths.push(gadget_script.len());
+                    let uses_op_cat = gadget_script.instructions().any(
+                        |instruction| matches!(instruction, Ok(Instruction::Op(op)) if op == OP_CAT),
+                    );
+                    if uses_op_cat && !options.op_cat {
+                        return Err(Error::msg(format!(
+                            "The gadget {} at trace entry {} uses OP_CAT, but OP_CAT is disabled",
+                            name, trace_idx
+                        )));
+                    }
+                    requires_op_cat |= uses_op_cat;
+
+                    // in strict mode, the declared stack effect is also checked against the script
+                    // itself, by executing it on the predicted stack, unless the gadget takes
+                    // elements that an earlier gadget left on the altstack, which are not known here
+                    if let Some(spec) =
+                        spec.filter(|spec| options.strict && spec.altstack_delta >= 0)
+                    {
+                        let mut witness =
+                            predict_stack(&cs, &stack, &all_hints[num_pulled_hints..])?;
+                        let num_below_inputs = witness.len();
+                        witness.extend(
+                            inputs
+                                .iter()
+                                .map(|idx| cs.memory.get(idx).unwrap().to_bytes()),
+                        );
+                        let num_produced = execute_prefix(gadget_script.as_bytes(), witness)
+                            .map_err(|e| {
+                                Error::msg(format!(
+                                    "The gadget at trace entry {} cannot be executed on its inputs: {}",
+                                    trace_idx, e
+                                ))
+                            })?
+                            .len()
+                            .checked_sub(num_below_inputs)
+                            .ok_or_else(|| {
+                                Error::msg(format!(
+                                    "The gadget at trace entry {} removes elements below its inputs",
+                                    trace_idx
+                                ))
+                            })?;
+                        if num_produced != spec.produces {
+                            return Err(Error::msg(format!(
+                                "The gadget at trace entry {} declares to produce {} elements, but its script leaves {} elements",
+                                trace_idx, spec.produces, num_produced
+                            )));
+                        }
+                    }
+                    script.extend_from_slice(gadget_script.as_bytes());
+
+                    let altstack_delta = altstack_delta(&gadget_script)?;
+                    let expected_altstack_delta = spec.map_or(0, |spec| spec.altstack_delta);
+                    if altstack_delta != expected_altstack_delta {
+                        return Err(Error::msg(format!(
+                            "The gadget at trace entry {} changes the altstack depth by {}, but {} is expected",
+                            trace_idx, altstack_delta, expected_altstack_delta
+                        )));
+                    }
+                    // the depth is recorded in the stack, where later gadgets can read it
+                    for _ in 0..altstack_delta {
+                        stack.push_alt();
+                    }
+                    for _ in altstack_delta..0 {
+                        stack.pop_alt().map_err(|_| {
+                            Error::msg(format!(
+                                "The gadget at trace entry {} takes more elements from the altstack than there are",
+                                trace_idx
+                            ))
+                        })?;
+                    }
 
                     cur_time += 1;
                 }
@@ -108,24 +731,102 @@ impl Compiler {
                 TraceEntry::DeclareOutput(idx) => {
                     stack.push_to_stack(*idx)?;
                 }
-                TraceEntry::RequestHint(idx) => {
-                    hint.push(cs.memory.get(idx).unwrap().clone());
+                TraceEntry::RequestHint(idx, _) => {
+                    hint_sink(cs.memory.get(idx).unwrap())?;
                     stack.push_to_stack(*idx)?;
 
                     script.push(OP_DEPTH as u8);
                     script.push(OP_1SUB as u8);
                     script.push(OP_ROLL as u8);
+                    num_pulled_hints += 1;
                 }
                 TraceEntry::SystemOutput(_) => {}
+                TraceEntry::Drop(idx) => {
+                    // the element may have already been consumed by its last gadget
+                    if stack.is_present(*idx)? {
+                        if last_visit[*idx] >= cur_time || output.contains(idx) {
+                            return Err(Error::msg(format!(
+                                "The memory entry with index {} is dropped but is still used afterwards",
+                                idx
+                            )));
+                        }
+
+                        let pos = stack.get_relative_position(*idx)?;
+                        stack.pull(*idx)?;
+                        if pos == 1 {
+                            script.push(OP_NIP.to_u8());
+                        } else {
+                            script.extend_from_slice(roll_script(pos).as_bytes());
+                            script.push(OP_DROP.to_u8());
+                        }
+                    }
+                }
+            }
+
+            let stack_depth = all_hints.len() - num_pulled_hints
+                + stack.get_num_elements_in_stack()?
+                + stack.alt_depth();
+            if stack_depth > options.max_stack {
+                return Err(Error::msg(format!(
+                    "The stack and the altstack hold {} elements after trace entry {}, which exceeds the limit of {}",
+                    stack_depth, trace_idx, options.max_stack
+                )));
+            }
+            max_stack_depth = max_stack_depth.max(stack_depth);
+
+            if let Some(snapshots) = snapshots.as_deref_mut() {
+                if let TraceEntry::InsertScript(..) = trace_entry {
+                    last_gadget_idx = trace_idx;
+                }
+
+                let outputs_pending = matches!(
+                    cs.trace.get(trace_idx + 1),
+                    Some(TraceEntry::DeclareOutput(_))
+                );
+                if !outputs_pending {
+                    let predicted_stack =
+                        predict_stack(&cs, &stack, &all_hints[num_pulled_hints..])?;
+
+                    snapshots.push(TraceSnapshot {
+                        trace_idx: if let TraceEntry::DeclareOutput(_) = trace_entry {
+                            last_gadget_idx
+                        } else {
+                            trace_idx
+                        },
+                        script_offset: num_flushed_bytes + script.len(),
+                        stack: predicted_stack,
+                    });
+                }
+            }
+
+            if let Some(script_sink) = script_sink.as_deref_mut() {
+                script_sink.write_all(&script)?;
+                num_flushed_bytes += script.len();
+                altstack_balance += altstack_delta(bitcoin::Script::from_bytes(&script))?;
+                check_minimal_pushes(bitcoin::Script::from_bytes(&script))?;
+                script.clear();
+            }
+        }
+
+        // every output must have survived until the end of the program
+        for &idx in output.iter() {
+            if !stack.is_present(idx)? {
+                return Err(Error::msg(format!(
+                    "The program output with memory index {} is no longer in the stack",
+                    idx
+                )));
             }
         }
 
         // step 4: move the desired output to the altstack
         let mut output_list_rev = output.clone();
+        if options.reorder_outputs {
+            // staging the outputs from the top of the stack downwards keeps every pick/roll
+            // distance as short as possible
+            output_list_rev.sort_unstable();
+        }
         output_list_rev.reverse();
 
-        let mut output_total_len = 0;
-
         for (i, &idx) in output_list_rev.iter().enumerate() {
             // for each entry, roll or pick the data and then save the data to the altstack
             // - roll, if this is the last occurrence of this idx in `output_list_rev`
@@ -135,51 +836,235 @@ impl Compiler {
 
             let pos = stack.get_relative_position(idx)?;
 
-            if output_list_rev[i..].contains(&idx) {
+            if output_list_rev[i + 1..].contains(&idx) {
                 // pick
-                script.extend_from_slice(
-                    script! {
-                        { pos } OP_PICK
-                        OP_TOALTSTACK
-                    }
-                    .as_bytes(),
-                );
+                script.extend_from_slice(pick_script(pos).as_bytes());
             } else {
                 // roll
                 stack.pull(idx)?;
-                script.extend_from_slice(
-                    script! {
-                        { pos } OP_ROLL
-                        OP_TOALTSTACK
-                    }
-                    .as_bytes(),
-                );
+                script.extend_from_slice(roll_script(pos).as_bytes());
             }
-            output_total_len += 1;
+            script.push(OP_TOALTSTACK.to_u8());
+            stack.push_alt();
         }
 
+        // the outputs that are picked rather than rolled are copies, which add to the depth
+        let stack_depth = stack.get_num_elements_in_stack()? + stack.alt_depth();
+        if stack_depth > options.max_stack {
+            return Err(Error::msg(format!(
+                "The stack and the altstack hold {} elements once the outputs are staged, which exceeds the limit of {}",
+                stack_depth, options.max_stack
+            )));
+        }
+        max_stack_depth = max_stack_depth.max(stack_depth);
+
+        // the outputs come back from the altstack in the reverse order of staging
+        let mut output_order = output_list_rev.clone();
+        output_order.reverse();
+
+        let num_outputs = output.len();
+
         // clear all the remaining elements
         let elements_in_stack = stack.get_num_elements_in_stack()?;
-        for _ in 0..elements_in_stack / 2 {
-            script.push(OP_2DROP.to_u8());
-        }
-        if elements_in_stack % 2 == 1 {
-            script.push(OP_DROP.to_u8());
-        }
+        let num_residual_elements = match options.cleanup {
+            CleanupStrategy::Drop => {
+                for _ in 0..elements_in_stack / 2 {
+                    script.push(OP_2DROP.to_u8());
+                }
+                if elements_in_stack % 2 == 1 {
+                    script.push(OP_DROP.to_u8());
+                }
+                0
+            }
+            CleanupStrategy::Caller => elements_in_stack,
+        };
 
         // recover the output from the altstack
-        for _ in 0..output_total_len {
+        for _ in 0..num_outputs {
             script.push(OP_FROMALTSTACK.to_u8());
+            stack.pop_alt()?;
         }
 
+        // the gadgets may move elements between each other through the altstack, but the
+        // program as a whole must leave it empty
+        altstack_balance += altstack_delta(bitcoin::Script::from_bytes(&script))?;
+        check_minimal_pushes(bitcoin::Script::from_bytes(&script))?;
+        if altstack_balance != 0 {
+            return Err(Error::msg(format!(
+                "The script has {} more OP_TOALTSTACK than OP_FROMALTSTACK",
+                altstack_balance
+            )));
+        }
+
+        let output = output_order
+            .iter()
+            .map(|idx| cs.memory.get(idx).unwrap().clone())
+            .collect();
+
+        if let Some(script_sink) = script_sink {
+            script_sink.write_all(&script)?;
+            script.clear();
+        }
+
+        cs.gadget_script_lengths = gadget_script_lengths;
+
         Ok(CompiledProgram {
             input,
             script: ScriptBuf::from_bytes(script),
-            hint,
+            hint: vec![],
+            hint_descriptions,
+            num_residual_elements,
+            num_outputs,
+            requires_op_cat,
+            max_stack_depth,
+            output,
+            output_order,
+            script_streamed: false,
+            hints_streamed: false,
         })
     }
 }
 
+/// Returns the script that pushes `v` with the minimal encoding: the dedicated opcodes for -1 to
+/// 16, and otherwise the minimal script number encoding.
+pub fn minimal_push(v: i64) -> Vec<u8> {
+    match v {
+        0 => vec![OP_PUSHBYTES_0.to_u8()],
+        -1 => vec![OP_PUSHNUM_NEG1.to_u8()],
+        1..=16 => vec![OP_PUSHNUM_1.to_u8() + v as u8 - 1],
+        _ => {
+            let bytes = bitcoin_num_to_bytes(v);
+            let mut res = vec![bytes.len() as u8];
+            res.extend_from_slice(&bytes);
+            res
+        }
+    }
+}
+
+/// Returns the net number of elements that the script moves to the altstack, counted from its
+/// `OP_TOALTSTACK` and `OP_FROMALTSTACK` opcodes.
+/// Returns the stack that the compiler predicts, from the bottom to the top: the hints that have
+/// not been pulled yet, followed by the elements present in the stack in the order of their
+/// memory indices.
+fn predict_stack(
+    cs: &ConstraintSystem,
+    stack: &Stack,
+    remaining_hints: &[usize],
+) -> Result<Vec<Vec<u8>>> {
+    let mut predicted_stack = remaining_hints
+        .iter()
+        .map(|idx| cs.memory.get(idx).unwrap().to_bytes())
+        .collect::<Vec<_>>();
+    for idx in 0..cs.memory_last_idx {
+        if stack.is_present(idx)? {
+            predicted_stack.push(cs.memory.get(&idx).unwrap().to_bytes());
+        }
+    }
+    Ok(predicted_stack)
+}
+
+/// Counts the net number of elements that the script moves to the altstack. Since the count is
+/// taken from the opcodes rather than from an execution, both branches of every conditional must
+/// change the altstack depth by the same amount, and the script is rejected otherwise.
+fn altstack_delta(script: &bitcoin::Script) -> Result<isize> {
+    let mut delta = 0;
+    // for each open conditional, the delta when it is entered and at the end of its last branch
+    let mut conditionals: Vec<(isize, Option<isize>)> = vec![];
+    let unbalanced = || {
+        Error::msg(
+            "The branches of a conditional in the script change the altstack depth differently",
+        )
+    };
+    for instruction in script.instructions() {
+        let Instruction::Op(op) = instruction? else {
+            continue;
+        };
+        match op.to_u8() {
+            v if v == OP_TOALTSTACK.to_u8() => delta += 1,
+            v if v == OP_FROMALTSTACK.to_u8() => delta -= 1,
+            v if v == OP_IF.to_u8() || v == OP_NOTIF.to_u8() => conditionals.push((delta, None)),
+            v if v == OP_ELSE.to_u8() => {
+                let (start, branch) = conditionals.last_mut().ok_or_else(unbalanced)?;
+                if branch.is_some_and(|branch| branch != delta) {
+                    return Err(unbalanced());
+                }
+                *branch = Some(delta);
+                delta = *start;
+            }
+            v if v == OP_ENDIF.to_u8() => {
+                let (start, branch) = conditionals.pop().ok_or_else(unbalanced)?;
+                // without an else branch, the other branch leaves the altstack as it is
+                if branch.unwrap_or(start) != delta {
+                    return Err(unbalanced());
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(delta)
+}
+
+/// Checks that every push in the script uses the smallest opcode for its data, since non-minimal
+/// pushes are rejected by the standardness rules. This covers the pushes inside the gadgets, and
+/// not only the constants.
+fn check_minimal_pushes(script: &bitcoin::Script) -> Result<()> {
+    for instruction in script.instructions_minimal() {
+        if let Err(e) = instruction {
+            return Err(Error::msg(format!(
+                "The script has a push that is not minimal: {}",
+                e
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Reads back the hints written by `Compiler::compile_with_hint_writer`, in the witness encoding.
+pub fn read_streamed_hints<R: Read>(reader: &mut R) -> Result<Vec<Vec<u8>>> {
+    let mut hints = vec![];
+    loop {
+        let mut len_bytes = [0u8; 4];
+        match reader.read_exact(&mut len_bytes) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::UnexpectedEof => break,
+            Err(e) => return Err(e.into()),
+        }
+        let mut bytes = vec![0u8; u32::from_le_bytes(len_bytes) as usize];
+        reader.read_exact(&mut bytes)?;
+        hints.push(bytes);
+    }
+    Ok(hints)
+}
+
+/// Returns the length of the script that brings two different inputs to the top of the stack in
+/// the given order, without changing the stack.
+fn two_inputs_staging_len(
+    stack: &mut Stack,
+    first: usize,
+    second: usize,
+    roll_first: bool,
+    roll_second: bool,
+) -> Result<usize> {
+    let first_pos = stack.get_relative_position(first)?;
+    let mut second_pos = stack.get_relative_position(second)?;
+    if roll_first && first_pos < second_pos {
+        second_pos -= 1;
+    }
+
+    let first_len = if roll_first {
+        roll_script(first_pos).len()
+    } else {
+        pick_script(first_pos).len()
+    };
+    let second_len = if roll_second {
+        roll_script(second_pos + 1).len()
+    } else {
+        pick_script(second_pos + 1).len()
+    };
+
+    Ok(first_len + second_len)
+}
+
 fn roll_script(distance: usize) -> Script {
     if distance == 0 {
         script! {} // do nothing, it is already on the top of the stack
@@ -215,3 +1100,1050 @@ fn pick_script(distance: usize) -> Script {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::builtins::bool::BoolVar;
+    use crate::builtins::hash::{bitcoin_num_to_bytes, HashVar};
+    use crate::builtins::i32::I32Var;
+    use crate::builtins::str::StrVar;
+    use crate::bvar::AllocVar;
+    use crate::compiler::{
+        execute_prefix, find_first_divergence, minimal_push, read_streamed_hints, CleanupStrategy,
+        Compiler, CompilerOptions,
+    };
+    use crate::constraint_system::{ConstraintSystem, Element};
+    use crate::options::Options;
+    use crate::script_generator::GadgetSpec;
+    use crate::stack::Stack;
+    use crate::treepp::*;
+    use crate::{test_program, test_program_without_opcat};
+    use anyhow::Result;
+    use bitcoin::opcodes::all::{OP_ADD, OP_DUP, OP_FROMALTSTACK, OP_SWAP, OP_TOALTSTACK};
+    use bitcoin::script::Instruction;
+    use bitcoin::secp256k1::{Secp256k1, XOnlyPublicKey};
+    use bitcoin::taproot::{LeafVersion, TaprootBuilder};
+    use std::str::FromStr;
+
+    fn add_gadget(_: &mut Stack, _: &Options) -> Result<Script> {
+        Ok(script! {
+            OP_ADD
+        })
+    }
+
+    fn pulling_gadget(stack: &mut Stack, options: &Options) -> Result<Script> {
+        stack.pull(options.get_u32("idx")? as usize)?;
+        Ok(script! {})
+    }
+
+    fn leaking_gadget(_: &mut Stack, _: &Options) -> Result<Script> {
+        Ok(script! {
+            OP_TOALTSTACK
+        })
+    }
+
+    #[test]
+    fn test_to_witness() {
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_program_input(&cs, 3).unwrap();
+        let b = I32Var::new_hint(&cs, -300).unwrap();
+        let c = &a + &b;
+        cs.set_program_output(&c).unwrap();
+
+        let program = Compiler::compile(cs.clone()).unwrap();
+        assert_eq!(
+            program.to_witness().unwrap(),
+            vec![bitcoin_num_to_bytes(-300), bitcoin_num_to_bytes(3)]
+        );
+
+        test_program(cs, script! { -297 }).unwrap();
+    }
+
+    #[test]
+    fn test_cleanup_strategy() {
+        let cs = ConstraintSystem::new_ref();
+
+        for i in 0..200 {
+            let _ = I32Var::new_constant(&cs, i).unwrap();
+        }
+        let a = I32Var::new_constant(&cs, 1000).unwrap();
+        cs.set_program_output(&a).unwrap();
+
+        let dropped = Compiler::compile(cs.clone()).unwrap();
+        let kept = Compiler::compile_with_options(
+            cs,
+            &CompilerOptions::new().with_cleanup(CleanupStrategy::Caller),
+        )
+        .unwrap();
+
+        assert_eq!(dropped.num_residual_elements, 0);
+        assert_eq!(kept.num_residual_elements, 200);
+        assert_eq!(
+            dropped.script.len() - kept.script.len(),
+            kept.num_residual_elements.div_ceil(2)
+        );
+    }
+
+    #[test]
+    fn test_gadget_spec() {
+        for (produces, expected_ok) in [(1, true), (2, false)] {
+            let cs = ConstraintSystem::new_ref();
+
+            let a = I32Var::new_constant(&cs, 3).unwrap();
+            let b = I32Var::new_constant(&cs, 4).unwrap();
+            cs.insert_script_complex_with_spec(
+                "add_gadget",
+                add_gadget,
+                [a.variable, b.variable],
+                &Options::new(),
+                GadgetSpec {
+                    consumes: 2,
+                    produces,
+                    altstack_delta: 0,
+                    commutative: false,
+                },
+            )
+            .unwrap();
+            let c = I32Var::new_function_output(&cs, 7).unwrap();
+            cs.set_program_output(&c).unwrap();
+
+            assert_eq!(Compiler::compile(cs).is_ok(), expected_ok);
+        }
+
+        // the spec agrees with the trace, but not with what the script does
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_program_input(&cs, 3).unwrap();
+        let b = I32Var::new_program_input(&cs, 4).unwrap();
+        let outputs = cs
+            .insert_raw(
+                script! { OP_ADD OP_DUP },
+                &[a.variable, b.variable],
+                &[Element::Num(7)],
+            )
+            .unwrap();
+        let c = I32Var {
+            variable: outputs[0],
+            value: 7,
+            cs: cs.clone(),
+        };
+        cs.set_program_output(&c).unwrap();
+
+        // the script is only executed in strict mode
+        assert!(Compiler::compile(cs.clone()).is_ok());
+        let err = Compiler::compile_with_options(cs, &CompilerOptions::new().with_strict(true))
+            .err()
+            .unwrap();
+        assert!(err.to_string().contains("its script leaves 2 elements"));
+
+        // the script reads an element below its input, which is on the predicted stack
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_program_input(&cs, 3).unwrap();
+        let b = I32Var::new_program_input(&cs, 4).unwrap();
+        let outputs = cs
+            .insert_raw(
+                script! { OP_OVER OP_ADD },
+                &[b.variable],
+                &[Element::Num(7)],
+            )
+            .unwrap();
+        let c = I32Var {
+            variable: outputs[0],
+            value: 7,
+            cs: cs.clone(),
+        };
+        cs.set_program_output(&c).unwrap();
+        cs.set_program_output(&a).unwrap();
+
+        Compiler::compile_with_options(cs.clone(), &CompilerOptions::new().with_strict(true))
+            .unwrap();
+        test_program(cs, script! { 7 3 }).unwrap();
+    }
+
+    #[test]
+    fn test_altstack_leak() {
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_constant(&cs, 3).unwrap();
+        cs.insert_script_complex(
+            "leaking_gadget",
+            leaking_gadget,
+            [a.variable],
+            &Options::new(),
+        )
+        .unwrap();
+
+        // the gadget does not report its use of the altstack, which is found from its script
+        let err = Compiler::compile(cs).err().unwrap();
+        assert!(err.to_string().contains("changes the altstack depth by 1"));
+
+        // the gadget declares its use of the altstack, but nothing brings the element back
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_constant(&cs, 3).unwrap();
+        cs.insert_script_complex_with_spec(
+            "leaking_gadget",
+            leaking_gadget,
+            [a.variable],
+            &Options::new(),
+            GadgetSpec {
+                consumes: 1,
+                produces: 0,
+                altstack_delta: 1,
+                commutative: false,
+            },
+        )
+        .unwrap();
+
+        let err = Compiler::compile(cs).err().unwrap();
+        assert!(err
+            .to_string()
+            .contains("1 more OP_TOALTSTACK than OP_FROMALTSTACK"));
+    }
+
+    fn conditional_stash_gadget() -> Script {
+        script! {
+            OP_IF OP_TOALTSTACK OP_ELSE OP_DROP OP_ENDIF
+        }
+    }
+
+    fn conditional_copy_gadget() -> Script {
+        script! {
+            OP_IF OP_TOALTSTACK OP_ELSE OP_NEGATE OP_TOALTSTACK OP_ENDIF
+            OP_FROMALTSTACK
+        }
+    }
+
+    #[test]
+    fn test_conditional_altstack() {
+        // only one branch moves the element to the altstack, which cannot be counted statically
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_program_input(&cs, 3).unwrap();
+        let b = BoolVar::new_program_input(&cs, true).unwrap();
+        cs.insert_script(
+            "conditional_stash_gadget",
+            conditional_stash_gadget,
+            [a.variable, b.variable],
+        )
+        .unwrap();
+
+        let err = Compiler::compile(cs).err().unwrap();
+        assert!(err
+            .to_string()
+            .contains("change the altstack depth differently"));
+
+        // both branches move one element, which the gadget brings back
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_program_input(&cs, 3).unwrap();
+        let b = BoolVar::new_program_input(&cs, false).unwrap();
+        cs.insert_script(
+            "conditional_copy_gadget",
+            conditional_copy_gadget,
+            [a.variable, b.variable],
+        )
+        .unwrap();
+        let c = I32Var::new_function_output(&cs, -3).unwrap();
+        cs.set_program_output(&c).unwrap();
+
+        test_program(cs, script! { -3 }).unwrap();
+    }
+
+    #[test]
+    fn test_unreachable_output() {
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_constant(&cs, 3).unwrap();
+        cs.set_program_output(&a).unwrap();
+        cs.insert_script_complex(
+            "pulling_gadget",
+            pulling_gadget,
+            [],
+            &Options::new().with_u32("idx", a.variable as u32),
+        )
+        .unwrap();
+
+        assert!(Compiler::compile(cs).is_err());
+    }
+
+    #[test]
+    fn test_reorder_outputs() {
+        let cs = ConstraintSystem::new_ref();
+
+        let vars = (0..10)
+            .map(|i| I32Var::new_constant(&cs, i).unwrap())
+            .collect::<Vec<_>>();
+        for var in vars.iter().rev() {
+            cs.set_program_output(var).unwrap();
+        }
+
+        let declared = Compiler::compile(cs.clone()).unwrap();
+        let reordered =
+            Compiler::compile_with_options(cs, &CompilerOptions::new().with_reorder_outputs(true))
+                .unwrap();
+
+        assert_eq!(declared.num_outputs, 10);
+        assert_eq!(reordered.num_outputs, 10);
+        assert!(reordered.script.len() < declared.script.len());
+
+        // the outputs are declared from 9 to 0, but reordering sorts them by memory index
+        let exec_script = script! {
+            { reordered.script }
+            for i in (0..10).rev() {
+                { i } OP_EQUALVERIFY
+            }
+            OP_TRUE
+        };
+        let exec_result = execute_script(exec_script);
+        assert!(exec_result.success);
+    }
+
+    #[test]
+    fn test_repeated_output() {
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_program_input(&cs, 3).unwrap();
+        cs.set_program_output(&a).unwrap();
+        cs.set_program_output(&a).unwrap();
+
+        // the first staging of the output picks it, and the last one rolls it, so that nothing is
+        // left below the outputs
+        let program = Compiler::compile_with_options(
+            cs.clone(),
+            &CompilerOptions::new().with_cleanup(CleanupStrategy::Caller),
+        )
+        .unwrap();
+        assert_eq!(program.num_residual_elements, 0);
+        assert_eq!(
+            program.opcodes().unwrap(),
+            vec![
+                Instruction::Op(OP_DUP),
+                Instruction::Op(OP_TOALTSTACK),
+                Instruction::Op(OP_TOALTSTACK),
+                Instruction::Op(OP_FROMALTSTACK),
+                Instruction::Op(OP_FROMALTSTACK),
+            ]
+        );
+
+        test_program(cs, script! { 3 3 }).unwrap();
+    }
+
+    #[test]
+    fn test_streamed_hints() {
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_program_input(&cs, 3).unwrap();
+        let mut sum = a;
+        for i in 0..5 {
+            let b = I32Var::new_hint(&cs, i * 1000 - 2000).unwrap();
+            sum = &sum + &b;
+        }
+        cs.set_program_output(&sum).unwrap();
+
+        let inline = Compiler::compile(cs.clone()).unwrap();
+
+        let mut buffer = vec![];
+        let streamed =
+            Compiler::compile_with_hint_writer(cs, &CompilerOptions::new(), &mut buffer).unwrap();
+        assert!(streamed.hint.is_empty());
+        assert_eq!(streamed.script, inline.script);
+
+        let mut witness = read_streamed_hints(&mut buffer.as_slice()).unwrap();
+        witness.extend(streamed.input.iter().map(|x| x.to_bytes()));
+        assert_eq!(witness, inline.to_witness().unwrap());
+
+        // the streamed program does not pass off its inputs alone as the witness
+        assert!(streamed.to_witness().is_err());
+        assert!(inline.then(streamed).is_err());
+    }
+
+    #[test]
+    fn test_requires_op_cat() {
+        let cs = ConstraintSystem::new_ref();
+
+        let a = StrVar::new_program_input(&cs, b"hello".to_vec()).unwrap();
+        let b = StrVar::new_constant(&cs, b" world".to_vec()).unwrap();
+        let c = &a + &b;
+        cs.set_program_output(&c).unwrap();
+
+        assert!(Compiler::compile(cs.clone()).unwrap().requires_op_cat);
+        assert!(
+            test_program_without_opcat(cs.clone(), script! { { b"hello world".to_vec() } })
+                .is_err()
+        );
+        test_program(cs, script! { { b"hello world".to_vec() } }).unwrap();
+
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_program_input(&cs, 3).unwrap();
+        let b = I32Var::new_constant(&cs, 4).unwrap();
+        let c = &a + &b;
+        cs.set_program_output(&c).unwrap();
+
+        assert!(!Compiler::compile(cs.clone()).unwrap().requires_op_cat);
+        test_program_without_opcat(cs, script! { 7 }).unwrap();
+    }
+
+    #[test]
+    fn test_op_cat_disabled() {
+        let cs = ConstraintSystem::new_ref();
+
+        let a = HashVar::new_program_input(&cs, vec![1u8; 32]).unwrap();
+        let b = HashVar::new_program_input(&cs, vec![2u8; 32]).unwrap();
+        let c = &a + &b;
+        cs.set_program_output(&c).unwrap();
+
+        let err =
+            Compiler::compile_with_options(cs.clone(), &CompilerOptions::new().with_op_cat(false))
+                .err()
+                .unwrap();
+        assert!(err.to_string().contains("hash_combine"));
+        assert!(test_program_without_opcat(cs.clone(), script! { { c.value.clone() } }).is_err());
+        test_program(cs, script! { { c.value.clone() } }).unwrap();
+    }
+
+    #[test]
+    fn test_opcodes() {
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_program_input(&cs, 3).unwrap();
+        let b = I32Var::new_program_input(&cs, 4).unwrap();
+        cs.insert_script_complex(
+            "add_gadget",
+            add_gadget,
+            [a.variable, b.variable],
+            &Options::new(),
+        )
+        .unwrap();
+        let c = I32Var::new_function_output(&cs, 7).unwrap();
+        cs.set_program_output(&c).unwrap();
+
+        let program = Compiler::compile(cs).unwrap();
+        assert_eq!(
+            program.opcodes().unwrap(),
+            vec![
+                Instruction::Op(OP_SWAP),
+                Instruction::Op(OP_SWAP),
+                Instruction::Op(OP_ADD),
+                Instruction::Op(OP_TOALTSTACK),
+                Instruction::Op(OP_FROMALTSTACK),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_compile_split() {
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_program_input(&cs, 3).unwrap();
+        let b = I32Var::new_hint(&cs, -300).unwrap();
+        let c = I32Var::new_hint(&cs, 12).unwrap();
+        let d = &(&a + &b) - &c;
+        cs.set_program_output(&d).unwrap();
+
+        let program = Compiler::compile(cs.clone()).unwrap();
+        let (hint_loader, body) = Compiler::compile_split(cs).unwrap();
+        assert_eq!(body, program.script);
+
+        let exec_script = script! {
+            { hint_loader }
+            3
+            { body }
+            -309 OP_EQUALVERIFY
+            OP_TRUE
+        };
+        let exec_result = execute_script(exec_script);
+        assert!(exec_result.success);
+    }
+
+    #[test]
+    fn test_commutative() {
+        let mut lens = vec![];
+        for commutative in [false, true] {
+            let cs = ConstraintSystem::new_ref();
+
+            let a = I32Var::new_program_input(&cs, 3).unwrap();
+            let b = I32Var::new_program_input(&cs, 4).unwrap();
+            cs.insert_script_complex_with_spec(
+                "add_gadget",
+                add_gadget,
+                [a.variable, b.variable],
+                &Options::new(),
+                GadgetSpec {
+                    commutative,
+                    ..GadgetSpec::commutative_binary()
+                },
+            )
+            .unwrap();
+            let c = I32Var::new_function_output(&cs, 7).unwrap();
+            cs.set_program_output(&c).unwrap();
+
+            lens.push(Compiler::compile(cs.clone()).unwrap().script.len());
+            test_program(cs, script! { 7 }).unwrap();
+        }
+
+        // `a` below `b` takes two swaps, while `b` then `a` takes only one
+        assert_eq!(lens[0], lens[1] + 1);
+    }
+
+    fn buggy_add_gadget() -> Script {
+        script! {
+            OP_SUB
+        }
+    }
+
+    #[test]
+    fn test_compile_and_trace() {
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_program_input(&cs, 3).unwrap();
+        let b = I32Var::new_hint(&cs, 4).unwrap();
+        let c = &a + &b;
+        let d = I32Var::new_constant(&cs, 5).unwrap();
+        let buggy_gadget_idx = cs.0.borrow().trace.len();
+        cs.insert_script(
+            "buggy_add_gadget",
+            buggy_add_gadget,
+            [c.variable, d.variable],
+        )
+        .unwrap();
+        let e = I32Var::new_function_output(&cs, 12).unwrap();
+        cs.set_program_output(&e).unwrap();
+
+        let (program, snapshots) = Compiler::compile_and_trace(cs).unwrap();
+        assert_eq!(
+            find_first_divergence(&program, &snapshots).unwrap(),
+            Some(buggy_gadget_idx)
+        );
+
+        // the snapshots before the buggy gadget match the execution
+        let buggy_snapshot = snapshots
+            .iter()
+            .position(|snapshot| snapshot.trace_idx == buggy_gadget_idx)
+            .unwrap();
+        assert!(buggy_snapshot > 0);
+        assert_eq!(
+            find_first_divergence(&program, &snapshots[..buggy_snapshot]).unwrap(),
+            None
+        );
+    }
+
+    #[test]
+    fn test_execute_prefix() {
+        assert_eq!(
+            execute_prefix(script! { OP_DUP }.as_bytes(), vec![vec![1]]).unwrap(),
+            vec![vec![1], vec![1]]
+        );
+
+        // a failure in the middle of the script is reported rather than returning the stack
+        let err =
+            execute_prefix(script! { OP_VERIFY OP_DUP }.as_bytes(), vec![vec![]]).unwrap_err();
+        assert!(err.to_string().contains("The execution fails"));
+    }
+
+    #[test]
+    fn test_drop_var() {
+        let mut depths = vec![];
+        for drop in [false, true] {
+            let cs = ConstraintSystem::new_ref();
+
+            let a = I32Var::new_program_input(&cs, 1).unwrap();
+            let mut sum = a.clone();
+            for i in 0..50 {
+                let b = I32Var::new_constant(&cs, i).unwrap();
+                sum = &sum + &b;
+            }
+
+            // intermediate values that are never used again
+            let intermediates = (0..50)
+                .map(|i| I32Var::new_constant(&cs, i).unwrap())
+                .collect::<Vec<_>>();
+            if drop {
+                for intermediate in intermediates.iter() {
+                    cs.drop_var(intermediate).unwrap();
+                }
+            }
+            for i in 0..50 {
+                let _ = I32Var::new_constant(&cs, i).unwrap();
+            }
+            cs.set_program_output(&sum).unwrap();
+
+            depths.push(Compiler::compile(cs.clone()).unwrap().max_stack_depth);
+            test_program(cs, script! { 1226 }).unwrap();
+        }
+
+        assert!(depths[1] + 50 <= depths[0]);
+    }
+
+    #[test]
+    fn test_drop_var_used_later() {
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_program_input(&cs, 1).unwrap();
+        let b = I32Var::new_constant(&cs, 2).unwrap();
+        cs.drop_var(&a).unwrap();
+        let c = &a + &b;
+        cs.set_program_output(&c).unwrap();
+
+        assert!(Compiler::compile(cs).is_err());
+    }
+
+    #[test]
+    fn test_minimal_push() {
+        assert_eq!(minimal_push(0), vec![0x00]);
+        assert_eq!(minimal_push(-1), vec![0x4f]);
+        assert_eq!(minimal_push(1), vec![0x51]);
+        assert_eq!(minimal_push(16), vec![0x60]);
+        assert_eq!(minimal_push(17), vec![0x01, 0x11]);
+        assert_eq!(minimal_push(127), vec![0x01, 0x7f]);
+        assert_eq!(minimal_push(128), vec![0x02, 0x80, 0x00]);
+        assert_eq!(minimal_push(255), vec![0x02, 0xff, 0x00]);
+        assert_eq!(minimal_push(256), vec![0x02, 0x00, 0x01]);
+        assert_eq!(minimal_push(-127), vec![0x01, 0xff]);
+        assert_eq!(minimal_push(-128), vec![0x02, 0x80, 0x80]);
+        assert_eq!(minimal_push(-256), vec![0x02, 0x00, 0x81]);
+    }
+
+    #[test]
+    fn test_constant_pushes() {
+        let values = [0, -1, 16, 127, 128, 255, 256, -128, i32::MAX];
+        let cs = ConstraintSystem::new_ref();
+        for &v in values.iter() {
+            let var = I32Var::new_constant(&cs, v).unwrap();
+            cs.set_program_output(&var).unwrap();
+        }
+
+        let program = Compiler::compile(cs.clone()).unwrap();
+        let pushes = values
+            .iter()
+            .flat_map(|&v| minimal_push(v as i64))
+            .collect::<Vec<_>>();
+        assert!(program.script.as_bytes().starts_with(&pushes));
+
+        test_program(
+            cs,
+            script! {
+                for v in values.iter() {
+                    { *v }
+                }
+            },
+        )
+        .unwrap();
+    }
+
+    fn non_minimal_push_gadget(_: &mut Stack, _: &Options) -> Result<Script> {
+        // pushes 5 with `OP_PUSHDATA1` instead of `OP_5`
+        Ok(Script::from_bytes(vec![0x4c, 0x01, 0x05, OP_ADD.to_u8()]))
+    }
+
+    #[test]
+    fn test_non_minimal_push_in_gadget() {
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_program_input(&cs, 3).unwrap();
+        cs.insert_script_complex(
+            "non_minimal_push_gadget",
+            non_minimal_push_gadget,
+            [a.variable],
+            &Options::new(),
+        )
+        .unwrap();
+        let b = I32Var::new_function_output(&cs, 8).unwrap();
+        cs.set_program_output(&b).unwrap();
+
+        let err = Compiler::compile(cs).err().unwrap();
+        assert!(err.to_string().contains("not minimal"));
+    }
+
+    #[test]
+    fn test_output_order() {
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_constant(&cs, 1).unwrap();
+        let b = I32Var::new_constant(&cs, 2).unwrap();
+        let c = I32Var::new_constant(&cs, 3).unwrap();
+        cs.set_program_output(&c).unwrap();
+        cs.set_program_output(&a).unwrap();
+        cs.set_program_output(&b).unwrap();
+
+        let program = Compiler::compile(cs.clone()).unwrap();
+        assert_eq!(
+            program.output_order,
+            vec![c.variable, a.variable, b.variable]
+        );
+        let expected = program
+            .output_order
+            .iter()
+            .map(|&idx| cs.get_element(idx).unwrap())
+            .collect::<Vec<_>>();
+        test_program(
+            cs.clone(),
+            script! {
+                for elem in expected.iter() {
+                    { elem }
+                }
+            },
+        )
+        .unwrap();
+
+        let reordered = Compiler::compile_with_options(
+            cs.clone(),
+            &CompilerOptions::new().with_reorder_outputs(true),
+        )
+        .unwrap();
+        assert_eq!(
+            reordered.output_order,
+            vec![a.variable, b.variable, c.variable]
+        );
+        let exec_script = script! {
+            { reordered.script }
+            for &idx in reordered.output_order.iter().rev() {
+                { &cs.get_element(idx).unwrap() } OP_EQUALVERIFY
+            }
+            OP_TRUE
+        };
+        assert!(execute_script(exec_script).success);
+    }
+
+    #[test]
+    fn test_max_stack() {
+        let cs = ConstraintSystem::new_ref();
+
+        for i in 0..1001 {
+            let _ = I32Var::new_constant(&cs, i).unwrap();
+        }
+
+        let err = Compiler::compile(cs.clone()).err().unwrap();
+        assert!(err.to_string().contains("trace entry 1000"));
+
+        let program =
+            Compiler::compile_with_options(cs, &CompilerOptions::new().with_max_stack(2000))
+                .unwrap();
+        assert_eq!(program.max_stack_depth, 1001);
+    }
+
+    fn to_altstack_gadget() -> Script {
+        script! {
+            OP_TOALTSTACK
+        }
+    }
+
+    fn from_altstack_gadget() -> Script {
+        script! {
+            OP_FROMALTSTACK
+        }
+    }
+
+    #[test]
+    fn test_max_stack_altstack() {
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_constant(&cs, 3).unwrap();
+        cs.insert_script_with_spec(
+            "to_altstack_gadget",
+            to_altstack_gadget,
+            [a.variable],
+            GadgetSpec {
+                consumes: 1,
+                produces: 0,
+                altstack_delta: 1,
+                commutative: false,
+            },
+        )
+        .unwrap();
+        let b = I32Var::new_constant(&cs, 4).unwrap();
+        cs.insert_script_with_spec(
+            "from_altstack_gadget",
+            from_altstack_gadget,
+            [],
+            GadgetSpec {
+                consumes: 0,
+                produces: 1,
+                altstack_delta: -1,
+                commutative: false,
+            },
+        )
+        .unwrap();
+        let c = I32Var::new_function_output(&cs, 3).unwrap();
+        let d = &b + &c;
+        cs.set_program_output(&d).unwrap();
+
+        // `a` is on the altstack while `b` is on the stack
+        assert_eq!(Compiler::compile(cs.clone()).unwrap().max_stack_depth, 2);
+        let err =
+            Compiler::compile_with_options(cs.clone(), &CompilerOptions::new().with_max_stack(1))
+                .err()
+                .unwrap();
+        assert!(err.to_string().contains("after trace entry 2"));
+
+        test_program(cs, script! { 7 }).unwrap();
+    }
+
+    #[test]
+    fn test_large_program() {
+        let cs = ConstraintSystem::new_ref();
+
+        let mut sum = I32Var::new_program_input(&cs, 0).unwrap();
+        for _ in 0..50000 {
+            let one = I32Var::new_constant(&cs, 1).unwrap();
+            sum = &sum + &one;
+        }
+        cs.set_program_output(&sum).unwrap();
+
+        // the output is the last memory entry, at the boundary of the stack's Fenwick tree
+        assert_eq!(sum.variable, 100000);
+        assert_eq!(cs.0.borrow().memory_last_idx, 100001);
+
+        let program = Compiler::compile(cs).unwrap();
+        assert_eq!(program.output_order, vec![100000]);
+        assert_eq!(program.max_stack_depth, 2);
+    }
+
+    #[test]
+    fn test_check_standardness() {
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_program_input(&cs, 3).unwrap();
+        let b = I32Var::new_constant(&cs, 4).unwrap();
+        let c = &a + &b;
+        cs.set_program_output(&c).unwrap();
+        Compiler::compile(cs).unwrap().check_standardness().unwrap();
+
+        let cs = ConstraintSystem::new_ref();
+
+        let mut sum = I32Var::new_program_input(&cs, 0).unwrap();
+        for _ in 0..3000 {
+            let a = I32Var::new_constant(&cs, 1000).unwrap();
+            sum = &sum + &a;
+        }
+        cs.set_program_output(&sum).unwrap();
+
+        let program = Compiler::compile(cs).unwrap();
+        assert!(program.script.len() > 10000);
+        let err = program.check_standardness().unwrap_err();
+        assert!(err.to_string().contains("exceeds the limit of 10000"));
+
+        let cs = ConstraintSystem::new_ref();
+
+        let a = StrVar::new_program_input(&cs, vec![0u8; 600]).unwrap();
+        cs.set_program_output(&a).unwrap();
+
+        let err = Compiler::compile(cs)
+            .unwrap()
+            .check_standardness()
+            .unwrap_err();
+        assert!(err.to_string().contains("600 bytes"));
+
+        let cs = ConstraintSystem::new_ref();
+
+        let a = StrVar::new_constant(&cs, vec![0u8; 600]).unwrap();
+        cs.set_program_output(&a).unwrap();
+
+        let err = Compiler::compile(cs)
+            .unwrap()
+            .check_standardness()
+            .unwrap_err();
+        assert!(err.to_string().contains("pushes 600 bytes"));
+
+        let cs = ConstraintSystem::new_ref();
+
+        let a = StrVar::new_program_input(&cs, b"hello".to_vec()).unwrap();
+        let b = StrVar::new_constant(&cs, b" world".to_vec()).unwrap();
+        let c = &a + &b;
+        cs.set_program_output(&c).unwrap();
+
+        let err = Compiler::compile(cs)
+            .unwrap()
+            .check_standardness()
+            .unwrap_err();
+        assert!(err.to_string().contains("OP_CAT"));
+    }
+
+    #[test]
+    fn test_psbt_input_witness() {
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_program_input(&cs, 3).unwrap();
+        let b = I32Var::new_hint(&cs, 4).unwrap();
+        let c = &a + &b;
+        cs.set_program_output(&c).unwrap();
+
+        let program = Compiler::compile(cs).unwrap();
+
+        let secp = Secp256k1::verification_only();
+        // the unspendable internal key suggested in BIP-341
+        let internal_key = XOnlyPublicKey::from_str(
+            "50929b74c1a04954b78b4b6035e97a5e078a5a0f28ec96d547bfee9ace803ac0",
+        )
+        .unwrap();
+        let spend_info = TaprootBuilder::new()
+            .add_leaf(0, program.script.clone())
+            .unwrap()
+            .finalize(&secp, internal_key)
+            .unwrap();
+        let control_block = spend_info
+            .control_block(&(program.script.clone(), LeafVersion::TapScript))
+            .unwrap();
+        assert!(control_block.verify_taproot_commitment(
+            &secp,
+            spend_info.output_key().to_inner(),
+            &program.script
+        ));
+
+        let witness = program.to_psbt_input_witness(&control_block).unwrap();
+        let elements = witness.iter().map(|elem| elem.to_vec()).collect::<Vec<_>>();
+        assert_eq!(elements.len(), program.to_witness().unwrap().len() + 2);
+        assert_eq!(elements[elements.len() - 1], control_block.serialize());
+
+        let (stack, leaf) = elements.split_at(elements.len() - 2);
+        assert_eq!(leaf[0], program.script.to_bytes());
+        assert_eq!(
+            execute_prefix(&leaf[0], stack.to_vec()).unwrap(),
+            vec![bitcoin_num_to_bytes(7)]
+        );
+    }
+
+    #[test]
+    fn test_hint_descriptions() {
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_program_input(&cs, 300).unwrap();
+        let b = I32Var::new_hint(&cs, 4).unwrap();
+        let c = &a + &b;
+        let limbs = c.to_positive_limbs(2, 8).unwrap();
+        cs.describe_hint(b.variable, "four").unwrap();
+        cs.set_program_output_vec(&limbs).unwrap();
+
+        assert!(cs.describe_hint(a.variable, "input").is_err());
+
+        let program = Compiler::compile(cs).unwrap();
+        assert_eq!(
+            program.hint_descriptions,
+            vec![
+                Some("four".to_string()),
+                Some(format!("limb 0 of {}", c.variable)),
+                Some(format!("limb 1 of {}", c.variable)),
+            ]
+        );
+        assert_eq!(program.hint_descriptions.len(), program.hint.len());
+    }
+
+    #[test]
+    fn test_compile_streaming() {
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_program_input(&cs, 3).unwrap();
+        let b = I32Var::new_hint(&cs, -300).unwrap();
+        let mut sum = &a + &b;
+        for i in 0..10 {
+            let c = I32Var::new_constant(&cs, i).unwrap();
+            sum = &sum + &c;
+        }
+        cs.set_program_output(&sum).unwrap();
+        cs.set_program_output(&a).unwrap();
+
+        let options = CompilerOptions::new().with_reorder_outputs(true);
+
+        let mut buffer = vec![];
+        let streamed = Compiler::compile_streaming(cs.clone(), &options, &mut buffer).unwrap();
+        assert!(streamed.script.is_empty());
+
+        let program = Compiler::compile_with_options(cs, &options).unwrap();
+        assert_eq!(buffer, program.script.to_bytes());
+        assert_eq!(streamed.hint, program.hint);
+        assert_eq!(streamed.output_order, program.output_order);
+
+        // the methods that need the script do not mistake the streamed program for an empty one
+        assert!(streamed.check_standardness().is_err());
+        assert!(streamed.opcodes().is_err());
+        assert!(streamed.then(program).is_err());
+    }
+
+    #[test]
+    fn test_strict_no_outputs() {
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_program_input(&cs, 3).unwrap();
+        let b = I32Var::new_constant(&cs, 4).unwrap();
+        let _ = &a + &b;
+        assert_eq!(cs.num_outputs(), 0);
+
+        assert!(Compiler::compile_with_options(
+            cs.clone(),
+            &CompilerOptions::new().with_strict(true)
+        )
+        .is_err());
+        assert_eq!(Compiler::compile(cs).unwrap().num_outputs, 0);
+
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_program_input(&cs, 3).unwrap();
+        cs.set_program_output(&a).unwrap();
+        assert_eq!(cs.num_outputs(), 1);
+
+        Compiler::compile_with_options(cs, &CompilerOptions::new().with_strict(true)).unwrap();
+    }
+
+    #[test]
+    fn test_then() {
+        let compile_first = || {
+            let cs = ConstraintSystem::new_ref();
+
+            let a = I32Var::new_program_input(&cs, 3).unwrap();
+            let b = I32Var::new_hint(&cs, 4).unwrap();
+            let c = &a + &b;
+            cs.set_program_output(&c).unwrap();
+
+            Compiler::compile(cs).unwrap()
+        };
+
+        let compile_second = |input: i32| {
+            let cs = ConstraintSystem::new_ref();
+
+            let a = I32Var::new_program_input(&cs, input).unwrap();
+            let b = I32Var::new_hint(&cs, 5).unwrap();
+            let c = &a.double().unwrap() + &b;
+            cs.set_program_output(&c).unwrap();
+
+            Compiler::compile(cs).unwrap()
+        };
+
+        assert_eq!(compile_first().output, vec![Element::Num(7)]);
+        assert!(compile_first().then(compile_second(8)).is_err());
+
+        let composed = compile_first().then(compile_second(7)).unwrap();
+        assert_eq!(composed.input, vec![Element::Num(3)]);
+        assert_eq!(composed.hint, vec![Element::Num(4), Element::Num(5)]);
+        assert_eq!(composed.output, vec![Element::Num(19)]);
+        assert_eq!(
+            execute_prefix(composed.script.as_bytes(), composed.to_witness().unwrap()).unwrap(),
+            vec![bitcoin_num_to_bytes(19)]
+        );
+    }
+
+    #[test]
+    fn test_strict_unused_hints() {
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_program_input(&cs, 3).unwrap();
+        let b = I32Var::new_hint(&cs, 4).unwrap();
+        let c = I32Var::new_hint(&cs, 5).unwrap();
+        let d = I32Var::new_hint(&cs, 6).unwrap();
+        let e = &a + &c;
+        cs.set_program_output(&e).unwrap();
+        cs.set_program_output(&d).unwrap();
+
+        let err =
+            Compiler::compile_with_options(cs.clone(), &CompilerOptions::new().with_strict(true))
+                .err()
+                .unwrap();
+        assert!(err
+            .to_string()
+            .contains(&format!("[{}] are never used", b.variable)));
+
+        test_program(cs, script! { 8 6 }).unwrap();
+    }
+}
@@ -1,14 +1,19 @@
-use crate::bvar::{AllocationMode, BVar};
+use crate::builtins;
+use crate::builtins::hash::bitcoin_num_to_bytes;
+use crate::bvar::{AllocationMode, BVar, ErasedBVar};
+use crate::error::DslError;
 use crate::options::Options;
-use crate::script_generator::ScriptGenerator;
+use crate::script_generator::{GadgetSpec, ScriptGenerator};
 use crate::stack::Stack;
 use crate::treepp::pushable::{Builder, Pushable};
 use crate::treepp::Script;
-use anyhow::{Error, Result};
+use anyhow::Result;
 use indexmap::IndexMap;
 use std::cell::RefCell;
 use std::cmp::PartialEq;
+use std::collections::{BTreeSet, HashMap};
 use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 /// A shared reference to a constraint system that can be stored in high level
 /// variables.
@@ -24,9 +29,24 @@ impl PartialEq for &ConstraintSystemRef {
 impl Eq for &ConstraintSystemRef {}
 
 impl ConstraintSystemRef {
+    /// Returns the constraint system shared by two variables, and panics if they belong to
+    /// different ones, see `try_and`.
     pub fn and(&self, other: &Self) -> Self {
-        assert_eq!(self, other);
-        self.clone()
+        self.try_and(other).unwrap_or_else(|e| panic!("{}", e))
+    }
+
+    /// Returns the constraint system shared by two variables, or an error if they belong to
+    /// different ones.
+    pub fn try_and(&self, other: &Self) -> Result<Self> {
+        if self != other {
+            return Err(DslError::DifferentConstraintSystems(self.id(), other.id()).into());
+        }
+        Ok(self.clone())
+    }
+
+    /// Returns the identifier of the constraint system, which is unique within the process.
+    pub fn id(&self) -> usize {
+        self.0.borrow().id
     }
 
     pub fn alloc(&self, data: Element, mode: AllocationMode) -> Result<usize> {
@@ -35,23 +55,88 @@ impl ConstraintSystemRef {
 
     pub fn insert_script_complex(
         &self,
+        name: &'static str,
         script_generator: fn(&mut Stack, &Options) -> Result<Script>,
         input_idxs: impl IntoIterator<Item = usize>,
         options: &Options,
     ) -> Result<()> {
         self.0
             .borrow_mut()
-            .insert_script_complex(script_generator, input_idxs, options)
+            .insert_script_complex(name, script_generator, input_idxs, options)
+    }
+
+    pub fn insert_script_complex_with_spec(
+        &self,
+        name: &'static str,
+        script_generator: fn(&mut Stack, &Options) -> Result<Script>,
+        input_idxs: impl IntoIterator<Item = usize>,
+        options: &Options,
+        spec: GadgetSpec,
+    ) -> Result<()> {
+        self.0.borrow_mut().insert_script_complex_with_spec(
+            name,
+            script_generator,
+            input_idxs,
+            options,
+            spec,
+        )
     }
 
     pub fn insert_script(
         &self,
+        name: &'static str,
         script_generator: fn() -> Script,
         input_idxs: impl IntoIterator<Item = usize>,
     ) -> Result<()> {
         self.0
             .borrow_mut()
-            .insert_script(script_generator, input_idxs, &Options::new())
+            .insert_script(name, script_generator, input_idxs, &Options::new())
+    }
+
+    pub fn insert_script_with_spec(
+        &self,
+        name: &'static str,
+        script_generator: fn() -> Script,
+        input_idxs: impl IntoIterator<Item = usize>,
+        spec: GadgetSpec,
+    ) -> Result<()> {
+        self.0.borrow_mut().insert_script_with_spec(
+            name,
+            script_generator,
+            input_idxs,
+            &Options::new(),
+            spec,
+        )
+    }
+
+    /// Splices a hand-written script that consumes `inputs` and leaves `outputs` on the stack,
+    /// and returns the memory indices of the outputs. In strict mode, the compiler checks that the
+    /// script leaves as many elements as there are outputs, but the values of the outputs are
+    /// always taken as they are given.
+    pub fn insert_raw(
+        &self,
+        script: Script,
+        inputs: &[usize],
+        outputs: &[Element],
+    ) -> Result<Vec<usize>> {
+        let mut cs = self.0.borrow_mut();
+        cs.insert_script_generator(
+            "raw",
+            ScriptGenerator::Raw(script),
+            inputs.to_vec(),
+            &Options::new(),
+            Some(GadgetSpec {
+                consumes: inputs.len(),
+                produces: outputs.len(),
+                altstack_delta: 0,
+                commutative: false,
+            }),
+        )?;
+
+        outputs
+            .iter()
+            .map(|output| cs.alloc(output.clone(), AllocationMode::FunctionOutput))
+            .collect()
     }
 
     pub fn get_element(&self, idx: usize) -> Result<Element> {
@@ -67,26 +152,121 @@ impl ConstraintSystemRef {
         Ok(self.0.borrow().get_str(idx)?.to_vec())
     }
 
-    pub fn set_program_output(&self, var: &impl BVar) -> Result<()> {
+    pub fn set_program_output(&self, var: &(impl ErasedBVar + ?Sized)) -> Result<()> {
         self.0.borrow_mut().set_program_output(var)
     }
+
+    pub fn set_program_output_vec(&self, vars: &[impl ErasedBVar]) -> Result<()> {
+        for var in vars.iter() {
+            self.set_program_output(var)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the number of program outputs declared so far.
+    pub fn num_outputs(&self) -> usize {
+        self.0
+            .borrow()
+            .trace
+            .iter()
+            .filter(|trace_entry| matches!(trace_entry, TraceEntry::SystemOutput(_)))
+            .count()
+    }
+
+    /// Tells the compiler that the variable is no longer needed, so that its stack elements can
+    /// be dropped right away instead of at the end of the program.
+    pub fn drop_var(&self, var: &impl BVar) -> Result<()> {
+        self.0.borrow_mut().drop_var(var)
+    }
+
+    /// Attaches a description to a hint, see `ConstraintSystem::describe_hint`.
+    pub fn describe_hint(&self, idx: usize, description: impl ToString) -> Result<()> {
+        self.0.borrow_mut().describe_hint(idx, description)
+    }
+
+    /// Rewrites the trace, see `ConstraintSystem::map_trace`.
+    pub fn map_trace(&self, f: impl FnMut(&TraceEntry) -> Vec<TraceEntry>) -> Result<()> {
+        self.0.borrow_mut().map_trace(f)
+    }
+
+    /// Records that the memory entries with the given indices form one logical variable.
+    pub fn group(&self, indices: &[usize], label: impl ToString) -> Result<()> {
+        self.0.borrow_mut().group(indices, label)
+    }
+
+    pub fn describe_memory(&self) -> String {
+        self.0.borrow().describe_memory()
+    }
+
+    /// Recomputes the outputs of the gadgets that have a reference model in Rust, see
+    /// `ConstraintSystem::dry_run`.
+    pub fn dry_run(&self) -> Result<Option<usize>> {
+        self.0.borrow().dry_run()
+    }
+
+    pub fn cost_breakdown(&self) -> Result<HashMap<String, (usize, usize)>> {
+        self.0.borrow().cost_breakdown()
+    }
+
+    pub fn add_trace_hook(
+        &self,
+        hook: impl Fn(&str, &ScriptGenerator, &[Element], &[Element]) -> Result<()> + 'static,
+    ) {
+        self.0.borrow_mut().hooks.0.push(Box::new(hook));
+    }
+}
+
+/// A callback that receives the name and the script generator of an inserted script, together
+/// with the values of its inputs and its outputs.
+pub type TraceHook = Box<dyn Fn(&str, &ScriptGenerator, &[Element], &[Element]) -> Result<()>>;
+
+#[derive(Default)]
+pub struct TraceHooks(pub Vec<TraceHook>);
+
+impl std::fmt::Debug for TraceHooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "TraceHooks({})", self.0.len())
+    }
 }
 
+/// The counter used to assign each constraint system a distinct identifier.
+static NEXT_CONSTRAINT_SYSTEM_ID: AtomicUsize = AtomicUsize::new(0);
+
 #[derive(Debug)]
 pub struct ConstraintSystem {
+    pub id: usize,
     pub memory: IndexMap<usize, Element>,
     pub memory_last_idx: usize,
     pub trace: Vec<TraceEntry>,
     pub num_inputs: Option<usize>,
     pub finalized: bool,
+    pub hooks: TraceHooks,
+    /// The position in the trace of the last inserted script, whose outputs have not been
+    /// checked by the hooks yet.
+    pub pending_hook_check: Option<usize>,
+    /// The length of the script generated for each inserted script, recorded by the compiler.
+    pub gadget_script_lengths: Vec<usize>,
+    /// The sets of memory indices that form one logical variable, with their labels, which are
+    /// only used for debugging.
+    pub groups: Vec<(Vec<usize>, String)>,
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum Element {
     Num(i32),
     Str(Vec<u8>),
 }
 
+impl Element {
+    /// Returns the stack element as it appears in a witness.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Element::Num(v) => bitcoin_num_to_bytes(*v as i64),
+            Element::Str(v) => v.clone(),
+        }
+    }
+}
+
 impl Pushable for &Element {
     fn bitcoin_script_push(&self, builder: Builder) -> Builder {
         match self {
@@ -98,21 +278,38 @@ impl Pushable for &Element {
 
 #[derive(Clone, Debug)]
 pub enum TraceEntry {
-    InsertScript(ScriptGenerator, Vec<usize>, Options),
+    /// A gadget, with the name under which it was inserted, which identifies it in the cost
+    /// breakdown and the reference models, and the number of function outputs declared right
+    /// after it.
+    InsertScript(
+        &'static str,
+        ScriptGenerator,
+        Vec<usize>,
+        Options,
+        Option<GadgetSpec>,
+        usize,
+    ),
     DeclareConstant(usize),
     DeclareOutput(usize),
-    RequestHint(usize),
+    /// A hint, with an optional description of how it is computed, see `describe_hint`.
+    RequestHint(usize, Option<String>),
     SystemOutput(usize),
+    Drop(usize),
 }
 
 impl ConstraintSystem {
     pub fn new() -> Self {
         Self {
+            id: NEXT_CONSTRAINT_SYSTEM_ID.fetch_add(1, Ordering::Relaxed),
             memory: IndexMap::new(),
             memory_last_idx: 0,
             trace: vec![],
             num_inputs: None,
             finalized: false,
+            hooks: TraceHooks::default(),
+            pending_hook_check: None,
+            gadget_script_lengths: vec![],
+            groups: vec![],
         }
     }
 
@@ -123,7 +320,11 @@ impl ConstraintSystem {
 
     pub fn alloc(&mut self, data: Element, mode: AllocationMode) -> Result<usize> {
         if self.finalized {
-            return Err(Error::msg("The constraint system has been finalized"));
+            return Err(DslError::Finalized.into());
+        }
+
+        if mode != AllocationMode::FunctionOutput {
+            self.run_pending_hooks()?;
         }
 
         if mode != AllocationMode::ProgramInput {
@@ -132,9 +333,7 @@ impl ConstraintSystem {
             }
         } else {
             if self.num_inputs.is_some() {
-                return Err(Error::msg(
-                    "Inputs can only be allocated before any execution or allocation for constants or hints",
-                ));
+                return Err(DslError::LateProgramInput.into());
             }
         }
 
@@ -142,122 +341,761 @@ impl ConstraintSystem {
         self.memory_last_idx += 1;
 
         if self.memory.get(&idx).is_some() {
-            return Err(Error::msg("Memory is corrupted"));
+            return Err(DslError::MemoryCorrupted.into());
         }
         self.memory.insert(idx, data);
 
         if mode == AllocationMode::Constant {
             self.trace.push(TraceEntry::DeclareConstant(idx));
         } else if mode == AllocationMode::Hint {
-            self.trace.push(TraceEntry::RequestHint(idx));
+            self.trace.push(TraceEntry::RequestHint(idx, None));
         } else if mode == AllocationMode::FunctionOutput {
+            // the output belongs to the gadget that the run of outputs follows, if any
+            let owner = self
+                .trace
+                .iter_mut()
+                .rev()
+                .find(|trace_entry| !matches!(trace_entry, TraceEntry::DeclareOutput(_)));
+            if let Some(TraceEntry::InsertScript(.., num_outputs)) = owner {
+                *num_outputs += 1;
+            }
             self.trace.push(TraceEntry::DeclareOutput(idx));
         }
 
         Ok(idx)
     }
 
-    pub fn set_program_output(&mut self, var: &impl BVar) -> Result<()> {
+    pub fn set_program_output(&mut self, var: &(impl ErasedBVar + ?Sized)) -> Result<()> {
         if self.finalized {
-            return Err(Error::msg("The constraint system has been finalized"));
+            return Err(DslError::Finalized.into());
         }
 
+        self.run_pending_hooks()?;
+
         let indices = var.variables();
         for &index in indices.iter() {
             if self.memory.get(&index).is_none() {
-                return Err(Error::msg(
-                    "Could not find the memory entry with the given index",
-                ));
+                return Err(DslError::OutOfRange(index).into());
             }
             self.trace.push(TraceEntry::SystemOutput(index));
         }
         Ok(())
     }
 
+    pub fn drop_var(&mut self, var: &impl BVar) -> Result<()> {
+        if self.finalized {
+            return Err(DslError::Finalized.into());
+        }
+
+        self.run_pending_hooks()?;
+
+        for index in BVar::variables(var) {
+            if self.memory.get(&index).is_none() {
+                return Err(DslError::OutOfRange(index).into());
+            }
+            self.trace.push(TraceEntry::Drop(index));
+        }
+        Ok(())
+    }
+
+    /// Attaches a description to the hint with the given memory index, which tells where the
+    /// hint comes from when the witness does not match. The descriptions are reported in
+    /// `CompiledProgram::hint_descriptions`.
+    pub fn describe_hint(&mut self, idx: usize, description: impl ToString) -> Result<()> {
+        if self.finalized {
+            return Err(DslError::Finalized.into());
+        }
+
+        for trace_entry in self.trace.iter_mut().rev() {
+            if let TraceEntry::RequestHint(i, d) = trace_entry {
+                if *i == idx {
+                    *d = Some(description.to_string());
+                    return Ok(());
+                }
+            }
+        }
+        Err(DslError::NotAHint(idx).into())
+    }
+
+    /// Replaces each trace entry with the entries returned by `f`, which allows peephole
+    /// optimizations performed outside of the DSL. The closure may keep some entries back and
+    /// return them later, and it may redirect the uses of a memory entry to another one that
+    /// holds the same value, but it cannot allocate new memory entries.
+    ///
+    /// The rewritten trace is checked: every memory entry must be declared once before it is
+    /// used, and must not be used after it is dropped. Since the compiler locates an element by
+    /// counting the elements above it with a higher index, every declaration must have a higher
+    /// index than all the elements still on the stack, and every gadget must be followed by as
+    /// many function outputs as it declared when it was inserted. If the check fails, the trace is
+    /// left unchanged.
+    pub fn map_trace(&mut self, mut f: impl FnMut(&TraceEntry) -> Vec<TraceEntry>) -> Result<()> {
+        if self.finalized {
+            return Err(DslError::Finalized.into());
+        }
+
+        self.run_pending_hooks()?;
+
+        let mut trace = vec![];
+        for trace_entry in self.trace.iter() {
+            trace.extend(f(trace_entry));
+        }
+
+        // an element leaves the stack at its last use by a gadget, unless it is a program output
+        let mut last_visit = vec![None; self.memory_last_idx];
+        let mut is_output = vec![false; self.memory_last_idx];
+        for (trace_idx, trace_entry) in trace.iter().enumerate() {
+            match trace_entry {
+                TraceEntry::InsertScript(_, _, inputs, ..) => {
+                    for &idx in inputs.iter().filter(|&&idx| idx < self.memory_last_idx) {
+                        last_visit[idx] = Some(trace_idx);
+                    }
+                }
+                TraceEntry::SystemOutput(idx) if *idx < self.memory_last_idx => {
+                    is_output[*idx] = true
+                }
+                _ => {}
+            }
+        }
+
+        // the inputs are declared from the start
+        let num_inputs = self.num_inputs.unwrap_or(self.memory_last_idx);
+        let mut declared = vec![false; self.memory_last_idx];
+        let mut dropped = vec![false; self.memory_last_idx];
+        declared[..num_inputs].fill(true);
+        let mut in_stack = (0..num_inputs).collect::<BTreeSet<_>>();
+
+        for (trace_idx, trace_entry) in trace.iter().enumerate() {
+            let invalid = |idx: usize| DslError::InvalidTrace { trace_idx, idx };
+            let indices = match trace_entry {
+                TraceEntry::InsertScript(_, _, inputs, .., num_outputs) => {
+                    let num_declared = trace[trace_idx + 1..]
+                        .iter()
+                        .take_while(|entry| matches!(entry, TraceEntry::DeclareOutput(_)))
+                        .count();
+                    if num_declared != *num_outputs {
+                        return Err(DslError::GadgetOutputsMismatch {
+                            trace_idx,
+                            expected: *num_outputs,
+                        }
+                        .into());
+                    }
+                    inputs.clone()
+                }
+                TraceEntry::DeclareConstant(idx)
+                | TraceEntry::DeclareOutput(idx)
+                | TraceEntry::RequestHint(idx, _) => {
+                    if *idx >= self.memory_last_idx
+                        || declared[*idx]
+                        || in_stack.last().is_some_and(|top| top > idx)
+                    {
+                        return Err(invalid(*idx).into());
+                    }
+                    declared[*idx] = true;
+                    in_stack.insert(*idx);
+                    continue;
+                }
+                TraceEntry::SystemOutput(idx) | TraceEntry::Drop(idx) => vec![*idx],
+            };
+
+            for &idx in indices.iter() {
+                if idx >= self.memory_last_idx || !declared[idx] || dropped[idx] {
+                    return Err(invalid(idx).into());
+                }
+            }
+            match trace_entry {
+                TraceEntry::InsertScript(..) => {
+                    for idx in indices {
+                        if last_visit[idx] == Some(trace_idx) && !is_output[idx] {
+                            in_stack.remove(&idx);
+                        }
+                    }
+                }
+                TraceEntry::Drop(idx) => {
+                    dropped[*idx] = true;
+                    in_stack.remove(idx);
+                }
+                _ => {}
+            }
+        }
+
+        self.trace = trace;
+        self.gadget_script_lengths.clear();
+        Ok(())
+    }
+
+    pub fn group(&mut self, indices: &[usize], label: impl ToString) -> Result<()> {
+        for index in indices.iter() {
+            if self.memory.get(index).is_none() {
+                return Err(DslError::OutOfRange(*index).into());
+            }
+        }
+        self.groups.push((indices.to_vec(), label.to_string()));
+        Ok(())
+    }
+
+    /// Lists the memory entries, one per line, together with the labels of the groups that they
+    /// belong to.
+    pub fn describe_memory(&self) -> String {
+        let mut res = String::new();
+        for (idx, element) in self.memory.iter() {
+            res.push_str(&format!("{}: {:?}", idx, element));
+            for (indices, label) in self.groups.iter() {
+                if let Some(pos) = indices.iter().position(|i| i == idx) {
+                    res.push_str(&format!(" [{}.{}]", label, pos));
+                }
+            }
+            res.push('\n');
+        }
+        res
+    }
+
+    /// Walks the trace and, for every gadget that has a reference model in Rust, recomputes its
+    /// outputs from its inputs and compares them with the values in memory. Returns the trace
+    /// index of the first gadget whose outputs do not match, which indicates a witness bug that
+    /// would only show up when executing the script.
+    pub fn dry_run(&self) -> Result<Option<usize>> {
+        let references = builtins::reference_evals();
+
+        for (trace_idx, trace_entry) in self.trace.iter().enumerate() {
+            let TraceEntry::InsertScript(name, _, inputs, options, ..) = trace_entry else {
+                continue;
+            };
+            let Some(reference) = references
+                .iter()
+                .find(|reference| reference.name() == *name)
+            else {
+                continue;
+            };
+
+            let mut input_values = vec![];
+            for idx in inputs.iter() {
+                input_values.push(self.get_element(*idx)?.clone());
+            }
+
+            let mut output_values = vec![];
+            for entry in self.trace[trace_idx + 1..].iter() {
+                match entry {
+                    TraceEntry::DeclareOutput(idx) => {
+                        output_values.push(self.get_element(*idx)?.clone())
+                    }
+                    _ => break,
+                }
+            }
+
+            if reference.eval(&input_values, options)? != output_values {
+                return Ok(Some(trace_idx));
+            }
+        }
+
+        Ok(None)
+    }
+
     pub fn get_num(&self, idx: usize) -> Result<i32> {
         if self.finalized {
-            return Err(Error::msg("The constraint system has been finalized"));
+            return Err(DslError::Finalized.into());
         }
 
         match self.memory.get(&idx) {
             Some(Element::Num(v)) => Ok(*v),
-            _ => Err(Error::msg(
-                "Cannot read the requested data in memory as a number",
-            )),
+            Some(_) => Err(DslError::TypeMismatch {
+                idx,
+                expected: "number",
+            }
+            .into()),
+            None => Err(DslError::OutOfRange(idx).into()),
         }
     }
 
     pub fn get_str(&self, idx: usize) -> Result<&[u8]> {
         if self.finalized {
-            return Err(Error::msg("The constraint system has been finalized"));
+            return Err(DslError::Finalized.into());
         }
 
         match self.memory.get(&idx) {
             Some(Element::Str(v)) => Ok(v.as_slice()),
-            _ => Err(Error::msg(
-                "Cannot read the requested data in memory as a string",
-            )),
+            Some(_) => Err(DslError::TypeMismatch {
+                idx,
+                expected: "string",
+            }
+            .into()),
+            None => Err(DslError::OutOfRange(idx).into()),
         }
     }
 
     pub fn get_element(&self, idx: usize) -> Result<&Element> {
         if self.finalized {
-            return Err(Error::msg("The constraint system has been finalized"));
+            return Err(DslError::Finalized.into());
         }
 
         match self.memory.get(&idx) {
             Some(v) => Ok(v),
-            _ => Err(Error::msg("Cannot read the requested data in memory")),
+            None => Err(DslError::OutOfRange(idx).into()),
         }
     }
 
     pub fn insert_script(
         &mut self,
+        name: &'static str,
         script_generator: fn() -> Script,
         input_idxs: impl IntoIterator<Item = usize>,
         options: &Options,
     ) -> Result<()> {
-        if self.finalized {
-            return Err(Error::msg("The constraint system has been finalized"));
-        }
-
-        if self.num_inputs.is_none() {
-            self.num_inputs = Some(self.memory_last_idx);
-        }
-
-        self.trace.push(TraceEntry::InsertScript(
+        self.insert_script_generator(
+            name,
             ScriptGenerator::Simple(script_generator),
             input_idxs.into_iter().collect(),
-            options.clone(),
-        ));
+            options,
+            None,
+        )
+    }
 
-        Ok(())
+    pub fn insert_script_with_spec(
+        &mut self,
+        name: &'static str,
+        script_generator: fn() -> Script,
+        input_idxs: impl IntoIterator<Item = usize>,
+        options: &Options,
+        spec: GadgetSpec,
+    ) -> Result<()> {
+        self.insert_script_generator(
+            name,
+            ScriptGenerator::Simple(script_generator),
+            input_idxs.into_iter().collect(),
+            options,
+            Some(spec),
+        )
     }
 
     pub fn insert_script_complex(
         &mut self,
+        name: &'static str,
         script_generator: fn(&mut Stack, &Options) -> Result<Script>,
         input_idxs: impl IntoIterator<Item = usize>,
         options: &Options,
+    ) -> Result<()> {
+        self.insert_script_generator(
+            name,
+            ScriptGenerator::Complex(script_generator),
+            input_idxs.into_iter().collect(),
+            options,
+            None,
+        )
+    }
+
+    pub fn insert_script_complex_with_spec(
+        &mut self,
+        name: &'static str,
+        script_generator: fn(&mut Stack, &Options) -> Result<Script>,
+        input_idxs: impl IntoIterator<Item = usize>,
+        options: &Options,
+        spec: GadgetSpec,
+    ) -> Result<()> {
+        self.insert_script_generator(
+            name,
+            ScriptGenerator::Complex(script_generator),
+            input_idxs.into_iter().collect(),
+            options,
+            Some(spec),
+        )
+    }
+
+    fn insert_script_generator(
+        &mut self,
+        name: &'static str,
+        script_generator: ScriptGenerator,
+        input_idxs: Vec<usize>,
+        options: &Options,
+        spec: Option<GadgetSpec>,
     ) -> Result<()> {
         if self.finalized {
-            return Err(Error::msg("The constraint system has been finalized"));
+            return Err(DslError::Finalized.into());
         }
 
+        self.run_pending_hooks()?;
+
         if self.num_inputs.is_none() {
             self.num_inputs = Some(self.memory_last_idx);
         }
 
         self.trace.push(TraceEntry::InsertScript(
-            ScriptGenerator::Complex(script_generator),
-            input_idxs.into_iter().collect(),
+            name,
+            script_generator,
+            input_idxs,
             options.clone(),
+            spec,
+            0,
         ));
 
+        if !self.hooks.0.is_empty() {
+            self.pending_hook_check = Some(self.trace.len() - 1);
+        }
+
         Ok(())
     }
 
+    /// Runs the hooks over the last inserted script, whose outputs are the function outputs
+    /// declared right after it.
+    pub fn run_pending_hooks(&mut self) -> Result<()> {
+        let Some(pos) = self.pending_hook_check.take() else {
+            return Ok(());
+        };
+
+        if let TraceEntry::InsertScript(name, script_generator, inputs, ..) = &self.trace[pos] {
+            let mut input_values = vec![];
+            for idx in inputs.iter() {
+                input_values.push(self.get_element(*idx)?.clone());
+            }
+
+            let mut output_values = vec![];
+            for trace_entry in self.trace[pos + 1..].iter() {
+                match trace_entry {
+                    TraceEntry::DeclareOutput(idx) => {
+                        output_values.push(self.get_element(*idx)?.clone())
+                    }
+                    _ => break,
+                }
+            }
+
+            for hook in self.hooks.0.iter() {
+                hook(name, script_generator, &input_values, &output_values)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns, for each gadget name, the number of invocations and the total length of the
+    /// scripts that they generate, excluding the stack manipulation to bring up their inputs. This
+    /// is only available after the constraint system is compiled.
+    pub fn cost_breakdown(&self) -> Result<HashMap<String, (usize, usize)>> {
+        let names = self
+            .trace
+            .iter()
+            .filter_map(|trace_entry| match trace_entry {
+                TraceEntry::InsertScript(name, ..) => Some(*name),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        if names.len() != self.gadget_script_lengths.len() {
+            return Err(DslError::NotCompiled.into());
+        }
+
+        let mut costs = HashMap::<String, (usize, usize)>::new();
+        for (name, &len) in names.iter().zip(self.gadget_script_lengths.iter()) {
+            let entry = costs.entry(name.to_string()).or_default();
+            entry.0 += 1;
+            entry.1 += len;
+        }
+
+        Ok(costs)
+    }
+
     pub fn finalize(&mut self) {
         self.finalized = true;
     }
 }
+
+#[cfg(test)]
+mod test {
+    use crate::builtins::i32::I32Var;
+    use crate::bvar::AllocVar;
+    use crate::compiler::Compiler;
+    use crate::constraint_system::{ConstraintSystem, Element, TraceEntry};
+    use crate::error::DslError;
+    use crate::test_program;
+    use crate::treepp::*;
+    use anyhow::Error;
+    use std::collections::HashMap;
+
+    fn add_gadget() -> Script {
+        script! {
+            OP_ADD
+        }
+    }
+
+    #[test]
+    fn test_trace_hook() {
+        let cs = ConstraintSystem::new_ref();
+        // only the additions are checked, which are told apart by their names
+        cs.add_trace_hook(|name, _, inputs, outputs| {
+            if name != "i32_add" && name != "add_gadget" {
+                return Ok(());
+            }
+            match (inputs, outputs) {
+                ([Element::Num(a), Element::Num(b)], [Element::Num(c)]) if a + b == *c => Ok(()),
+                _ => Err(Error::msg("The output is not the sum of the inputs")),
+            }
+        });
+
+        let a = I32Var::new_constant(&cs, 3).unwrap();
+        let b = I32Var::new_constant(&cs, 4).unwrap();
+        let c = &a + &b;
+        let _ = &c - &a;
+
+        // deliberately declare a wrong output for the addition
+        cs.insert_script("add_gadget", add_gadget, [a.variable, b.variable])
+            .unwrap();
+        let d = I32Var::new_function_output(&cs, 8).unwrap();
+
+        // this triggers the check of the last addition
+        assert!(cs.set_program_output(&d).is_err());
+        assert_eq!(c.value, 7);
+    }
+
+    #[test]
+    fn test_group() {
+        let cs = ConstraintSystem::new_ref();
+
+        let limbs = (0..4)
+            .map(|i| I32Var::new_constant(&cs, i).unwrap())
+            .collect::<Vec<_>>();
+        let other = I32Var::new_constant(&cs, 100).unwrap();
+        cs.group(
+            &limbs.iter().map(|limb| limb.variable).collect::<Vec<_>>(),
+            "qm31",
+        )
+        .unwrap();
+        assert!(cs.group(&[1000], "missing").is_err());
+
+        let description = cs.describe_memory();
+        let lines = description.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 5);
+        for (i, limb) in limbs.iter().enumerate() {
+            assert_eq!(
+                lines[i],
+                format!("{}: Num({}) [qm31.{}]", limb.variable, i, i)
+            );
+        }
+        assert_eq!(lines[4], format!("{}: Num(100)", other.variable));
+    }
+
+    #[test]
+    fn test_dry_run() {
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_program_input(&cs, 3).unwrap();
+        let b = I32Var::new_constant(&cs, 4).unwrap();
+        let c = &a + &b;
+        let add_idx = cs.0.borrow().trace.len() - 2;
+        let d = &c - &a;
+        cs.set_program_output(&d).unwrap();
+
+        assert_eq!(cs.dry_run().unwrap(), None);
+
+        // corrupt the result of the addition
+        cs.0.borrow_mut().memory.insert(c.variable, Element::Num(8));
+        assert_eq!(cs.dry_run().unwrap(), Some(add_idx));
+    }
+
+    #[test]
+    #[should_panic(expected = "different constraint systems")]
+    fn test_different_constraint_systems() {
+        let cs_a = ConstraintSystem::new_ref();
+        let cs_b = ConstraintSystem::new_ref();
+        assert_ne!(cs_a.id(), cs_b.id());
+
+        let a = I32Var::new_constant(&cs_a, 1).unwrap();
+        let b = I32Var::new_constant(&cs_b, 2).unwrap();
+        let _ = &a + &b;
+    }
+
+    #[test]
+    fn test_try_and() {
+        let cs_a = ConstraintSystem::new_ref();
+        let cs_b = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_constant(&cs_a, 1).unwrap();
+        let b = I32Var::new_constant(&cs_b, 2).unwrap();
+        assert!(cs_a.try_and(&cs_a).is_ok());
+
+        // the fallible operations report the mismatch instead of panicking
+        let err = a.checked_add(&b).err().unwrap();
+        assert_eq!(
+            err.downcast_ref::<DslError>(),
+            Some(&DslError::DifferentConstraintSystems(cs_a.id(), cs_b.id()))
+        );
+    }
+
+    #[test]
+    fn test_insert_raw() {
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_program_input(&cs, 3).unwrap();
+        let b = I32Var::new_program_input(&cs, 4).unwrap();
+        let outputs = cs
+            .insert_raw(
+                script! { OP_ADD },
+                &[a.variable, b.variable],
+                &[Element::Num(7)],
+            )
+            .unwrap();
+        assert_eq!(outputs.len(), 1);
+
+        let c = I32Var {
+            variable: outputs[0],
+            value: 7,
+            cs: cs.clone(),
+        };
+        let d = &c + &a;
+        cs.set_program_output(&d).unwrap();
+
+        test_program(cs, script! { 10 }).unwrap();
+    }
+
+    #[test]
+    fn test_dsl_error() {
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_program_input(&cs, 3).unwrap();
+        cs.set_program_output(&a).unwrap();
+
+        let err = cs.get_int(a.variable + 1).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<DslError>(),
+            Some(&DslError::OutOfRange(a.variable + 1))
+        );
+
+        Compiler::compile(cs.clone()).unwrap();
+        cs.0.borrow_mut().finalize();
+
+        let err = I32Var::new_constant(&cs, 4).err().unwrap();
+        match err.downcast_ref::<DslError>() {
+            Some(DslError::Finalized) => {}
+            _ => panic!("unexpected error: {}", err),
+        }
+    }
+
+    #[test]
+    fn test_map_trace() {
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_program_input(&cs, 3).unwrap();
+        let b = I32Var::new_constant(&cs, 5).unwrap();
+        let c = &a + &b;
+        let d = &c - &b;
+        cs.set_program_output(&d).unwrap();
+
+        let len_before = Compiler::compile(cs.clone()).unwrap().script.len();
+
+        // holds back an addition and its output, and removes them together with a following
+        // subtraction of the same value, whose output is replaced by the left operand
+        let mut held: Vec<TraceEntry> = vec![];
+        let mut replacement = None;
+        let mut replaced = HashMap::new();
+        cs.map_trace(|trace_entry| {
+            let mut res = vec![];
+            match (trace_entry, held.as_slice()) {
+                (TraceEntry::InsertScript("i32_add", ..), []) => {
+                    held.push(trace_entry.clone());
+                }
+                (TraceEntry::DeclareOutput(_), [_]) => held.push(trace_entry.clone()),
+                (
+                    TraceEntry::InsertScript("i32_sub", _, inputs, ..),
+                    [TraceEntry::InsertScript(_, _, add_inputs, ..), TraceEntry::DeclareOutput(sum)],
+                ) if inputs[0] == *sum && inputs[1] == add_inputs[1] => {
+                    replacement = Some(add_inputs[0]);
+                    held.clear();
+                }
+                (TraceEntry::DeclareOutput(idx), []) if replacement.is_some() => {
+                    replaced.insert(*idx, replacement.take().unwrap());
+                }
+                (TraceEntry::SystemOutput(idx), []) => {
+                    res.push(TraceEntry::SystemOutput(*replaced.get(idx).unwrap_or(idx)));
+                }
+                _ => {
+                    res.append(&mut held);
+                    res.push(trace_entry.clone());
+                }
+            }
+            res
+        })
+        .unwrap();
+
+        let len_after = Compiler::compile(cs.clone()).unwrap().script.len();
+        assert!(len_after < len_before);
+
+        test_program(cs, script! { 3 }).unwrap();
+    }
+
+    #[test]
+    fn test_map_trace_invalid() {
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_program_input(&cs, 3).unwrap();
+        let b = I32Var::new_constant(&cs, 5).unwrap();
+        let c = &a + &b;
+        cs.set_program_output(&c).unwrap();
+
+        // removing the declaration of the constant leaves it undeclared when it is used
+        let err = cs
+            .map_trace(|trace_entry| match trace_entry {
+                TraceEntry::DeclareConstant(_) => vec![],
+                _ => vec![trace_entry.clone()],
+            })
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<DslError>(),
+            Some(&DslError::InvalidTrace {
+                trace_idx: 0,
+                idx: b.variable
+            })
+        );
+
+        // removing the declaration of the output leaves the addition without its output
+        let err = cs
+            .map_trace(|trace_entry| match trace_entry {
+                TraceEntry::DeclareOutput(_) => vec![],
+                _ => vec![trace_entry.clone()],
+            })
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<DslError>(),
+            Some(&DslError::GadgetOutputsMismatch {
+                trace_idx: 1,
+                expected: 1
+            })
+        );
+        assert_eq!(cs.0.borrow().trace.len(), 4);
+
+        test_program(cs, script! { 8 }).unwrap();
+    }
+
+    #[test]
+    fn test_map_trace_out_of_order() {
+        let cs = ConstraintSystem::new_ref();
+
+        let a = I32Var::new_program_input(&cs, 3).unwrap();
+        let b = I32Var::new_constant(&cs, 5).unwrap();
+        let c = I32Var::new_constant(&cs, 6).unwrap();
+        let d = &a + &c;
+        cs.set_program_output(&d).unwrap();
+        cs.set_program_output(&b).unwrap();
+
+        // swapping the two constants pushes the first one above an element with a higher index
+        let mut held = None;
+        let err = cs
+            .map_trace(|trace_entry| match trace_entry {
+                TraceEntry::DeclareConstant(idx) if *idx == b.variable => {
+                    held = Some(trace_entry.clone());
+                    vec![]
+                }
+                TraceEntry::DeclareConstant(_) => {
+                    let mut res = vec![trace_entry.clone()];
+                    res.extend(held.take());
+                    res
+                }
+                _ => vec![trace_entry.clone()],
+            })
+            .unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<DslError>(),
+            Some(&DslError::InvalidTrace {
+                trace_idx: 1,
+                idx: b.variable
+            })
+        );
+
+        test_program(cs, script! { 9 5 }).unwrap();
+    }
+}
@@ -0,0 +1,76 @@
+use std::fmt::{Display, Formatter};
+
+/// The errors raised by the constraint system. They are returned wrapped in `anyhow::Error`, from
+/// which they can be recovered with `downcast_ref::<DslError>()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DslError {
+    /// The constraint system has been finalized and can no longer be read or modified.
+    Finalized,
+    /// A program input is allocated after an execution or an allocation of another kind.
+    LateProgramInput,
+    /// The memory entry to be allocated is already occupied.
+    MemoryCorrupted,
+    /// There is no memory entry with the given index.
+    OutOfRange(usize),
+    /// The memory entry with the given index does not hold the expected kind of element.
+    TypeMismatch { idx: usize, expected: &'static str },
+    /// The memory entry with the given index is not a hint.
+    NotAHint(usize),
+    /// The information is only available after the constraint system is compiled.
+    NotCompiled,
+    /// A rewritten trace uses a memory entry that is not declared, or declares it twice or below
+    /// an element that is still on the stack.
+    InvalidTrace { trace_idx: usize, idx: usize },
+    /// A gadget in a rewritten trace is not followed by the function outputs that it declared.
+    GadgetOutputsMismatch { trace_idx: usize, expected: usize },
+    /// The variables of an operation belong to the constraint systems with the given identifiers.
+    DifferentConstraintSystems(usize, usize),
+}
+
+impl Display for DslError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DslError::Finalized => write!(f, "The constraint system has been finalized"),
+            DslError::LateProgramInput => write!(
+                f,
+                "Inputs can only be allocated before any execution or allocation for constants or hints"
+            ),
+            DslError::MemoryCorrupted => write!(f, "Memory is corrupted"),
+            DslError::OutOfRange(idx) => {
+                write!(f, "Could not find the memory entry with index {}", idx)
+            }
+            DslError::TypeMismatch { idx, expected } => write!(
+                f,
+                "Cannot read the memory entry with index {} as a {}",
+                idx, expected
+            ),
+            DslError::NotAHint(idx) => {
+                write!(f, "The memory entry with index {} is not a hint", idx)
+            }
+            DslError::NotCompiled => write!(
+                f,
+                "The constraint system must be compiled before computing the cost breakdown"
+            ),
+            DslError::InvalidTrace { trace_idx, idx } => write!(
+                f,
+                "The trace entry {} uses or redeclares the memory entry with index {} incorrectly",
+                trace_idx, idx
+            ),
+            DslError::GadgetOutputsMismatch {
+                trace_idx,
+                expected,
+            } => write!(
+                f,
+                "The gadget at trace entry {} is not followed by the {} outputs that it declared",
+                trace_idx, expected
+            ),
+            DslError::DifferentConstraintSystems(a, b) => write!(
+                f,
+                "The variables belong to different constraint systems (#{} and #{})",
+                a, b
+            ),
+        }
+    }
+}
+
+impl std::error::Error for DslError {}
@@ -0,0 +1,131 @@
+use crate::builtins::i32::I32Var;
+use crate::bvar::AllocVar;
+use crate::constraint_system::ConstraintSystem;
+use crate::test_program;
+use crate::treepp::*;
+use anyhow::Result;
+use arbitrary::{Arbitrary, Unstructured};
+
+/// The maximal number of operations in a random program.
+pub const MAX_NUM_OPS: usize = 32;
+
+/// The range of the values that a random program allocates, which is far from the boundaries of
+/// `i32` so that a few additions do not overflow.
+const VALUE_RANGE: std::ops::RangeInclusive<i32> = -(1 << 24)..=(1 << 24);
+
+/// An operation of a random program. The operands refer to the variables allocated so far, and
+/// are taken modulo their number.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FuzzOp {
+    Constant(i32),
+    Hint(i32),
+    Add(usize, usize),
+    Sub(usize, usize),
+    Copy(usize),
+    Output(usize),
+}
+
+impl<'a> Arbitrary<'a> for FuzzOp {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        Ok(match u.int_in_range(0..=5)? {
+            0 => FuzzOp::Constant(u.int_in_range(VALUE_RANGE)?),
+            1 => FuzzOp::Hint(u.int_in_range(VALUE_RANGE)?),
+            2 => FuzzOp::Add(u.arbitrary()?, u.arbitrary()?),
+            3 => FuzzOp::Sub(u.arbitrary()?, u.arbitrary()?),
+            4 => FuzzOp::Copy(u.arbitrary()?),
+            _ => FuzzOp::Output(u.arbitrary()?),
+        })
+    }
+}
+
+/// A random program over I32Vars of bounded size.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FuzzProgram {
+    pub inputs: Vec<i32>,
+    pub ops: Vec<FuzzOp>,
+}
+
+impl<'a> Arbitrary<'a> for FuzzProgram {
+    fn arbitrary(u: &mut Unstructured<'a>) -> arbitrary::Result<Self> {
+        let num_inputs = u.int_in_range(0..=4)?;
+        let mut inputs = vec![];
+        for _ in 0..num_inputs {
+            inputs.push(u.int_in_range(VALUE_RANGE)?);
+        }
+
+        let num_ops = u.int_in_range(0..=MAX_NUM_OPS)?;
+        let mut ops = vec![];
+        for _ in 0..num_ops {
+            ops.push(u.arbitrary()?);
+        }
+
+        Ok(FuzzProgram { inputs, ops })
+    }
+}
+
+impl FuzzProgram {
+    /// Builds the program, then compiles and executes it, checking that the outputs match the
+    /// values computed in Rust. Operations that would overflow are skipped.
+    pub fn check(&self) -> Result<()> {
+        let cs = ConstraintSystem::new_ref();
+
+        let mut vars = vec![];
+        for &input in self.inputs.iter() {
+            vars.push(I32Var::new_program_input(&cs, input)?);
+        }
+
+        let mut outputs = vec![];
+        for op in self.ops.iter() {
+            match *op {
+                FuzzOp::Constant(v) => vars.push(I32Var::new_constant(&cs, v)?),
+                FuzzOp::Hint(v) => vars.push(I32Var::new_hint(&cs, v)?),
+                _ if vars.is_empty() => {}
+                FuzzOp::Add(a, b) => {
+                    if let Ok(c) = vars[a % vars.len()].checked_add(&vars[b % vars.len()]) {
+                        vars.push(c);
+                    }
+                }
+                FuzzOp::Sub(a, b) => {
+                    if let Ok(c) = vars[a % vars.len()].checked_sub(&vars[b % vars.len()]) {
+                        vars.push(c);
+                    }
+                }
+                FuzzOp::Copy(a) => vars.push(vars[a % vars.len()].copy()?),
+                FuzzOp::Output(a) => {
+                    let var = &vars[a % vars.len()];
+                    cs.set_program_output(var)?;
+                    outputs.push(var.value);
+                }
+            }
+        }
+
+        test_program(
+            cs,
+            script! {
+                for output in outputs {
+                    { output }
+                }
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::fuzz::FuzzProgram;
+    use arbitrary::{Arbitrary, Unstructured};
+    use rand::{Rng, SeedableRng};
+    use rand_chacha::ChaCha20Rng;
+
+    #[test]
+    fn test_random_programs() {
+        let mut prng = ChaCha20Rng::seed_from_u64(0);
+
+        for _ in 0..50 {
+            let bytes = (0..1024).map(|_| prng.gen()).collect::<Vec<u8>>();
+            let mut u = Unstructured::new(&bytes);
+            let program = FuzzProgram::arbitrary(&mut u).unwrap();
+            program.check().unwrap();
+        }
+    }
+}
@@ -1,11 +1,10 @@
 use crate::builtins::hash::HashVar;
 use crate::bvar::{AllocVar, BVar};
 use crate::constraint_system::ConstraintSystemRef;
-use anyhow::Result;
+use anyhow::{Error, Result};
 use sha2::Digest;
 use std::collections::HashMap;
 
-#[derive(Default)]
 pub struct LDM {
     pub name_to_id: HashMap<String, usize>,
     pub value_map: Vec<Vec<u8>>,
@@ -14,6 +13,15 @@ pub struct LDM {
     pub cs: Option<ConstraintSystemRef>,
     pub hash_var: Option<HashVar>,
     pub log: Vec<usize>,
+
+    /// The domain-separation tag, whose hash is the initial digest.
+    pub tag: Vec<u8>,
+}
+
+impl Default for LDM {
+    fn default() -> Self {
+        Self::new_with_tag(b"ldm")
+    }
 }
 
 impl LDM {
@@ -21,6 +29,18 @@ impl LDM {
         Self::default()
     }
 
+    pub fn new_with_tag(tag: &[u8]) -> LDM {
+        Self {
+            name_to_id: HashMap::new(),
+            value_map: vec![],
+            hash_map: vec![],
+            cs: None,
+            hash_var: None,
+            log: vec![],
+            tag: tag.to_vec(),
+        }
+    }
+
     pub fn init(&mut self, cs: &ConstraintSystemRef) -> Result<()> {
         if self.cs.is_some() {
             let read_hash = self.hash_var.as_ref().unwrap().value.clone();
@@ -28,9 +48,7 @@ impl LDM {
             self.hash_var = Some(HashVar::new_program_input(&cs, read_hash)?);
         } else {
             self.cs = Some(cs.clone());
-            let default_hash = sha2::Sha256::digest(b"ldm").to_vec();
-            let hash_var = HashVar::new_constant(&cs, default_hash)?;
-            self.hash_var = Some(hash_var);
+            self.hash_var = Some(HashVar::new_with_tag(cs, &self.tag)?);
         }
 
         Ok(())
@@ -45,7 +63,7 @@ impl LDM {
         let idx = self.value_map.len();
         self.name_to_id.insert(name.to_string(), idx);
 
-        self.value_map.push(bincode::serialize(&value.value()?)?);
+        self.value_map.push(value.value_bytes());
 
         let hash_var = HashVar::from(value);
         self.hash_map.push(hash_var.value.clone());
@@ -57,9 +75,37 @@ impl LDM {
     }
 
     pub fn read<T: AllocVar>(&mut self, name: impl ToString) -> Result<T> {
-        let idx = self.name_to_id[&name.to_string()];
+        let idx = self.get_id(&name.to_string())?;
+        let value = T::from_value_bytes(&self.value_map[idx])?;
+        self.read_value(idx, value)
+    }
+
+    pub fn read_expect<T: AllocVar>(
+        &mut self,
+        name: impl ToString,
+        expected: T::Value,
+    ) -> Result<T> {
+        let name = name.to_string();
+        let idx = self.get_id(&name)?;
+
+        let value = T::from_value_bytes(&self.value_map[idx])?;
+        if value != expected {
+            return Err(Error::msg(format!(
+                "The value stored under \"{}\" is {:?}, but {:?} is expected",
+                name, value, expected
+            )));
+        }
+        self.read_value(idx, value)
+    }
 
-        let value: T::Value = bincode::deserialize(&self.value_map[idx])?;
+    fn get_id(&self, name: &str) -> Result<usize> {
+        self.name_to_id
+            .get(name)
+            .copied()
+            .ok_or_else(|| Error::msg(format!("Nothing is stored under \"{}\"", name)))
+    }
+
+    fn read_value<T: AllocVar>(&mut self, idx: usize, value: T::Value) -> Result<T> {
         let v = T::new_hint(self.cs.as_ref().unwrap(), value)?;
 
         self.hash_var = Some(self.hash_var.as_ref().unwrap() + &HashVar::from(&v));
@@ -76,13 +122,25 @@ impl LDM {
         Ok(())
     }
 
+    /// Recomputes the digest of all the writes and reads so far purely from `hash_map` and `log`,
+    /// which is the value that `save()` outputs.
+    pub fn expected_root(&self) -> Vec<u8> {
+        let mut root = sha2::Sha256::digest(&self.tag).to_vec();
+        for &idx in self.log.iter() {
+            let mut sha256 = sha2::Sha256::new();
+            sha256.update(&self.hash_map[idx]);
+            sha256.update(&root);
+            root = sha256.finalize().to_vec();
+        }
+        root
+    }
+
     pub fn check(&self) -> Result<()> {
         let mut next_index_to_load = 0;
         let mut map = Vec::<HashVar>::new();
         let cs = self.cs.as_ref().unwrap();
 
-        let default_hash = sha2::Sha256::digest(b"ldm").to_vec();
-        let mut recomputed_hash_var = HashVar::new_constant(&cs, default_hash)?;
+        let mut recomputed_hash_var = HashVar::new_with_tag(cs, &self.tag)?;
 
         let mut log_iter = self.log.iter().peekable();
 
@@ -113,6 +171,7 @@ impl LDM {
 #[cfg(test)]
 mod test {
     use crate::builtins::hash::HashVar;
+    use crate::builtins::i32::I32Var;
     use crate::bvar::{AllocVar, BVar};
     use crate::constraint_system::ConstraintSystem;
     use crate::ldm::LDM;
@@ -122,6 +181,36 @@ mod test {
     use rand::{Rng, SeedableRng};
     use rand_chacha::ChaCha20Rng;
 
+    #[test]
+    fn test_ldm_read_expect() {
+        let mut ldm = LDM::new();
+
+        let cs = ConstraintSystem::new_ref();
+        ldm.init(&cs).unwrap();
+
+        let a = I32Var::new_constant(&cs, 123).unwrap();
+        ldm.write("a", &a).unwrap();
+        ldm.save().unwrap();
+
+        test_program(
+            cs,
+            script! {
+                { ldm.hash_var.as_ref().unwrap().value.clone() }
+            },
+        )
+        .unwrap();
+
+        let cs = ConstraintSystem::new_ref();
+        ldm.init(&cs).unwrap();
+
+        assert!(ldm.read_expect::<I32Var>("a", 124).is_err());
+        assert!(ldm.read_expect::<I32Var>("b", 123).is_err());
+        assert!(ldm.read::<I32Var>("b").is_err());
+
+        let a = ldm.read_expect::<I32Var>("a", 123).unwrap();
+        assert_eq!(a.value, 123);
+    }
+
     #[test]
     fn test_ldm() {
         let mut prng = ChaCha20Rng::seed_from_u64(0);
@@ -168,4 +257,49 @@ mod test {
         )
         .unwrap();
     }
+
+    #[test]
+    fn test_ldm_expected_root() {
+        let mut ldm = LDM::new();
+
+        let cs = ConstraintSystem::new_ref();
+        ldm.init(&cs).unwrap();
+        assert_eq!(ldm.expected_root(), ldm.hash_var.as_ref().unwrap().value);
+
+        let a = I32Var::new_constant(&cs, 123).unwrap();
+        let b = I32Var::new_constant(&cs, -45).unwrap();
+        ldm.write("a", &a).unwrap();
+        ldm.write("b", &b).unwrap();
+        let _ = ldm.read::<I32Var>("a").unwrap();
+        assert_eq!(ldm.expected_root(), ldm.hash_var.as_ref().unwrap().value);
+
+        let cs = ConstraintSystem::new_ref();
+        ldm.init(&cs).unwrap();
+
+        let _ = ldm.read::<I32Var>("b").unwrap();
+        let _ = ldm.read::<I32Var>("a").unwrap();
+        assert_eq!(ldm.expected_root(), ldm.hash_var.as_ref().unwrap().value);
+    }
+
+    #[test]
+    fn test_ldm_tag() {
+        let mut roots = vec![];
+        for tag in [b"ldm".as_slice(), b"another tag".as_slice()] {
+            let mut ldm = LDM::new_with_tag(tag);
+
+            let cs = ConstraintSystem::new_ref();
+            ldm.init(&cs).unwrap();
+
+            let a = I32Var::new_constant(&cs, 123).unwrap();
+            ldm.write("a", &a).unwrap();
+            ldm.save().unwrap();
+
+            let root = ldm.hash_var.as_ref().unwrap().value.clone();
+            assert_eq!(ldm.expected_root(), root);
+            test_program(cs, script! { { root.clone() } }).unwrap();
+            roots.push(root);
+        }
+
+        assert_ne!(roots[0], roots[1]);
+    }
 }
@@ -1,4 +1,4 @@
-use crate::compiler::Compiler;
+use crate::compiler::{Compiler, CompilerOptions};
 use crate::constraint_system::ConstraintSystemRef;
 use crate::treepp::*;
 use anyhow::{Error, Result};
@@ -15,6 +15,8 @@ pub mod bvar;
 
 pub mod constraint_system;
 
+pub mod error;
+
 pub mod stack;
 
 pub mod compiler;
@@ -23,6 +25,11 @@ pub mod options;
 
 pub mod script_generator;
 
+pub mod reference_eval;
+
+#[cfg(feature = "arbitrary")]
+pub mod fuzz;
+
 #[allow(missing_docs)]
 pub mod treepp {
     pub use bitcoin_script::{define_pushable, script};
@@ -44,42 +51,13 @@ pub fn test_program_without_opcat(cs: ConstraintSystemRef, expected_stack: Scrip
     test_program_generic(cs, expected_stack, false)
 }
 
-fn test_program_generic(
-    cs: ConstraintSystemRef,
-    expected_stack: Script,
+/// Executes the script as a tapscript leaf on top of the witness, until it either finishes or
+/// fails. The outcome is left in `Exec::result`.
+pub(crate) fn execute_tapscript(
+    script: Script,
+    witness: Vec<Vec<u8>>,
     opcat: bool,
-) -> Result<()> {
-    let program = Compiler::compile(cs)?;
-
-    let mut script = script! {
-        for elem in program.hint.iter() {
-            { elem }
-        }
-        for elem in program.input.iter() {
-            { elem }
-        }
-    }
-    .to_bytes();
-    script.extend_from_slice(program.script.as_bytes());
-
-    let expected_final_stack = convert_to_witness(expected_stack)
-        .map_err(|x| anyhow::Error::msg(format!("final stack parsing error: {:?}", x)))?;
-    for elem in expected_final_stack.iter().rev() {
-        script.extend_from_slice(
-            script! {
-                { elem.to_vec() }
-                OP_EQUALVERIFY
-            }
-            .as_bytes(),
-        );
-    }
-
-    script.push(OP_TRUE.to_u8());
-
-    let script = Script::from_bytes(script);
-
-    println!("script size: {}", script.len());
-
+) -> Result<Exec> {
     let mut options = Options::default();
     if !opcat {
         options.experimental.op_cat = false;
@@ -100,15 +78,44 @@ fn test_program_generic(
             taproot_annex_scriptleaf: Some((TapLeafHash::all_zeros(), None)),
         },
         script,
-        vec![],
+        witness,
     )
-    .expect("error creating exec");
+    .map_err(|e| Error::msg(format!("Cannot create the executor: {:?}", e)))?;
 
-    loop {
-        if exec.exec_next().is_err() {
-            break;
-        }
+    // `exec_next` returns an error once the execution is over, whether it succeeds or not
+    while exec.exec_next().is_ok() {}
+    Ok(exec)
+}
+
+fn test_program_generic(
+    cs: ConstraintSystemRef,
+    expected_stack: Script,
+    opcat: bool,
+) -> Result<()> {
+    let program = Compiler::compile_with_options(cs, &CompilerOptions::new().with_op_cat(opcat))?;
+    let witness = program.to_witness()?;
+
+    let mut script = program.script.to_bytes();
+
+    let expected_final_stack = convert_to_witness(expected_stack)
+        .map_err(|x| anyhow::Error::msg(format!("final stack parsing error: {:?}", x)))?;
+    for elem in expected_final_stack.iter().rev() {
+        script.extend_from_slice(
+            script! {
+                { elem.to_vec() }
+                OP_EQUALVERIFY
+            }
+            .as_bytes(),
+        );
     }
+
+    script.push(OP_TRUE.to_u8());
+
+    let script = Script::from_bytes(script);
+
+    println!("script size: {}", script.len());
+
+    let exec = execute_tapscript(script, witness, opcat)?;
     let res = exec.result().unwrap();
     if !res.success {
         println!("{:8}", FmtStack(exec.stack().clone()));
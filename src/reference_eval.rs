@@ -0,0 +1,132 @@
+use crate::bvar::{AllocationMode, ErasedBVar};
+use crate::constraint_system::{ConstraintSystem, ConstraintSystemRef, Element};
+use crate::options::Options;
+use crate::script_generator::ScriptGenerator;
+use crate::test_program;
+use crate::treepp::*;
+use anyhow::{Error, Result};
+use rand_chacha::ChaCha20Rng;
+
+/// A pure-Rust model of a gadget, which computes the outputs that the gadget is expected to
+/// leave on the stack.
+pub trait ReferenceEval {
+    /// Returns the name under which the gadget is inserted, which the trace entries are matched
+    /// against.
+    fn name(&self) -> &'static str;
+
+    /// Returns the gadget that is modelled.
+    fn generator(&self) -> ScriptGenerator;
+
+    /// Samples valid inputs for the gadget, together with the options to insert it with.
+    fn sample(&self, prng: &mut ChaCha20Rng) -> (Vec<Element>, Options);
+
+    /// Computes the expected outputs.
+    fn eval(&self, inputs: &[Element], options: &Options) -> Result<Vec<Element>>;
+}
+
+/// Reads the inputs of a gadget that takes `N` numbers.
+pub(crate) fn nums<const N: usize>(inputs: &[Element]) -> Result<[i32; N]> {
+    let mut res = [0; N];
+    if inputs.len() != N {
+        return Err(Error::msg(format!("Expected {} inputs", N)));
+    }
+    for (r, input) in res.iter_mut().zip(inputs.iter()) {
+        match input {
+            Element::Num(v) => *r = *v,
+            Element::Str(_) => return Err(Error::msg("Expected a number as the input")),
+        }
+    }
+    Ok(res)
+}
+
+/// Checks over random inputs that the script of the gadget leaves exactly the outputs computed
+/// by the reference.
+pub fn check_reference_eval(
+    reference: &dyn ReferenceEval,
+    prng: &mut ChaCha20Rng,
+    num_trials: usize,
+) -> Result<()> {
+    for _ in 0..num_trials {
+        let (inputs, options) = reference.sample(prng);
+        let outputs = reference.eval(&inputs, &options)?;
+
+        let cs = ConstraintSystem::new_ref();
+        let mut input_idxs = vec![];
+        for input in inputs.iter() {
+            input_idxs.push(cs.alloc(input.clone(), AllocationMode::ProgramInput)?);
+        }
+
+        match reference.generator() {
+            ScriptGenerator::Simple(f) => cs.insert_script(reference.name(), f, input_idxs)?,
+            ScriptGenerator::Complex(f) => {
+                cs.insert_script_complex(reference.name(), f, input_idxs, &options)?
+            }
+            ScriptGenerator::Raw(_) => {
+                return Err(Error::msg("A raw script cannot have a reference model"))
+            }
+        }
+
+        let mut output_vars = vec![];
+        for output in outputs.iter() {
+            output_vars.push(RawVar {
+                cs: cs.clone(),
+                variable: cs.alloc(output.clone(), AllocationMode::FunctionOutput)?,
+                value: output.clone(),
+            });
+        }
+        cs.set_program_output_vec(&output_vars)?;
+
+        test_program(
+            cs,
+            script! {
+                for output in outputs.iter() {
+                    { output }
+                }
+            },
+        )?;
+    }
+    Ok(())
+}
+
+/// A variable that is only known by its memory index.
+struct RawVar {
+    cs: ConstraintSystemRef,
+    variable: usize,
+    value: Element,
+}
+
+impl ErasedBVar for RawVar {
+    fn cs(&self) -> ConstraintSystemRef {
+        self.cs.clone()
+    }
+
+    fn variables(&self) -> Vec<usize> {
+        vec![self.variable]
+    }
+
+    fn value_bytes(&self) -> Vec<u8> {
+        self.value.to_bytes()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::builtins;
+    use crate::reference_eval::check_reference_eval;
+    use rand::SeedableRng;
+    use rand_chacha::ChaCha20Rng;
+
+    #[test]
+    fn test_reference_evals() {
+        let mut prng = ChaCha20Rng::seed_from_u64(0);
+
+        let references = builtins::reference_evals();
+        for (i, reference) in references.iter().enumerate() {
+            // the references are looked up by name, which must therefore be unique
+            assert!(references[..i]
+                .iter()
+                .all(|other| other.name() != reference.name()));
+            check_reference_eval(reference.as_ref(), &mut prng, 20).unwrap();
+        }
+    }
+}
@@ -3,10 +3,44 @@ use crate::stack::Stack;
 use crate::treepp::Script;
 use anyhow::Result;
 
+/// The declared stack effect of a gadget, which the compiler checks against the trace and, in
+/// strict mode, against the script of the gadget, executed on the predicted stack.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct GadgetSpec {
+    /// The number of stack elements that the gadget takes as inputs.
+    pub consumes: usize,
+    /// The number of stack elements that the gadget leaves as outputs.
+    pub produces: usize,
+    /// The net number of elements that the gadget leaves on the altstack, as counted from the
+    /// `OP_TOALTSTACK` and `OP_FROMALTSTACK` opcodes in its script. The branches of a conditional
+    /// in the script must change the altstack depth by the same amount.
+    pub altstack_delta: isize,
+    /// Whether the gadget is symmetric in its two inputs, in which case the compiler may bring
+    /// them to the top of the stack in whichever order is cheaper.
+    pub commutative: bool,
+}
+
+impl GadgetSpec {
+    /// The spec of a gadget that consumes two elements, produces one, and does not care about the
+    /// order of its inputs, such as an addition. The `i32` and `u8` additions are inserted with
+    /// it, which by default only adds a comparison of the spec with the trace, since the script
+    /// is executed only in strict mode.
+    pub fn commutative_binary() -> GadgetSpec {
+        GadgetSpec {
+            consumes: 2,
+            produces: 1,
+            altstack_delta: 0,
+            commutative: true,
+        }
+    }
+}
+
 #[derive(Clone, Debug)]
 pub enum ScriptGenerator {
     Simple(fn() -> Script),
     Complex(fn(&mut Stack, &Options) -> Result<Script>),
+    /// A hand-written script that is spliced as is.
+    Raw(Script),
 }
 
 impl ScriptGenerator {
@@ -14,6 +48,7 @@ impl ScriptGenerator {
         match self {
             ScriptGenerator::Simple(f) => Ok(f()),
             ScriptGenerator::Complex(f) => f(stack, options),
+            ScriptGenerator::Raw(script) => Ok(script.clone()),
         }
     }
 }
@@ -12,6 +12,7 @@ pub struct Stack {
     pub bitmap: Vec<StackElementStatus>,
     pub fenwick_tree: FenwickTree<isize>,
     pub size: usize,
+    pub altstack_depth: usize,
 }
 
 impl Stack {
@@ -20,6 +21,7 @@ impl Stack {
             bitmap: vec![StackElementStatus::ABSENT; size],
             fenwick_tree: FenwickTree::with_len(size),
             size,
+            altstack_depth: 0,
         }
     }
 
@@ -63,4 +65,24 @@ impl Stack {
     pub fn get_num_elements_in_stack(&self) -> Result<usize> {
         Ok(self.fenwick_tree.sum(0..self.size)? as usize)
     }
+
+    /// Records that an element is moved to the altstack.
+    pub fn push_alt(&mut self) {
+        self.altstack_depth += 1;
+    }
+
+    /// Records that an element is moved back from the altstack.
+    pub fn pop_alt(&mut self) -> Result<()> {
+        if self.altstack_depth == 0 {
+            return Err(Error::msg("The altstack is empty."));
+        }
+        self.altstack_depth -= 1;
+        Ok(())
+    }
+
+    /// Returns the number of elements on the altstack. The compiler records the elements that
+    /// each gadget leaves there, counted from the altstack opcodes of its script.
+    pub fn alt_depth(&self) -> usize {
+        self.altstack_depth
+    }
 }